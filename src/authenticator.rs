@@ -0,0 +1,117 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+
+use crate::toolkit;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The challenge has to be answered during this period
+const CHALLENGE_VALIDITY: Duration = Duration::from_secs(60);
+
+/// Checking the client credentials given during the greeting. The clients can either send one of
+/// the configured tokens as is, or prove that they know it by signing a challenge, so that the
+/// token never crosses the wire.
+/// The challenges are signed with a random server secret and bound to the client address, so
+/// the server doesn't need to remember the issued ones, only the answered ones until they expire,
+/// so that every challenge is accepted once
+pub struct Authenticator {
+    /// Token per client name
+    tokens: BTreeMap<String, String>,
+    server_secret: [u8; 32],
+    /// Answered challenges with the time they were issued at
+    used_challenges: Mutex<HashMap<String, SystemTime>>,
+}
+
+impl Authenticator {
+    pub fn new(tokens: BTreeMap<String, String>) -> Self {
+        let mut server_secret = [0; 32];
+        rand::thread_rng().fill_bytes(&mut server_secret);
+        Authenticator { tokens, server_secret, used_challenges: Mutex::new(HashMap::new()) }
+    }
+
+    /// Authentication is required only when there are tokens configured
+    pub fn is_enabled(&self) -> bool {
+        !self.tokens.is_empty()
+    }
+
+    pub fn token(&self, name: &str) -> Option<&str> {
+        self.tokens.get(name).map(|t| t.as_str())
+    }
+
+    /// Returns the name of the client owning the token
+    pub fn check_token(&self, token: &str) -> Result<String, String> {
+        self.tokens
+            .iter()
+            .find(|(_, expected)| toolkit::constant_time_eq(expected.as_bytes(), token.as_bytes()))
+            .map(|(name, _)| name.clone())
+            .ok_or_else(|| "Invalid token".to_owned())
+    }
+
+    /// The challenge is `<timestamp>.<random>.<signature>`, where the signature covers the client
+    /// name and address as well
+    pub fn create_challenge(&self, name: &str, peer: SocketAddr) -> Result<String, String> {
+        if !self.tokens.contains_key(name) {
+            return Err(format!("Unknown client {}", name));
+        }
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|e| e.to_string())?
+            .as_secs();
+        let mut random = [0; 16];
+        rand::thread_rng().fill_bytes(&mut random);
+        let payload = format!("{}.{}", timestamp, hex::encode(random));
+        let signature = hex::encode(self.sign_challenge(name, peer, &payload));
+        Ok(format!("{}.{}", payload, signature))
+    }
+
+    /// The response is the hex encoded HMAC-SHA256 of the challenge with the client token as the key
+    pub fn verify_challenge_response(&self, name: &str, peer: SocketAddr, challenge: &str, response: &str) -> Result<(), String> {
+        let token = self.tokens
+            .get(name)
+            .ok_or_else(|| format!("Unknown client {}", name))?;
+        let (payload, signature) = challenge
+            .rsplit_once('.')
+            .ok_or_else(|| "Invalid challenge".to_owned())?;
+        let signature = hex::decode(signature).map_err(|_| "Invalid challenge".to_owned())?;
+        if !toolkit::constant_time_eq(&self.sign_challenge(name, peer, payload), &signature) {
+            return Err("Invalid challenge".to_owned());
+        }
+        let timestamp: u64 = payload
+            .split('.')
+            .next()
+            .and_then(|t| t.parse().ok())
+            .ok_or_else(|| "Invalid challenge".to_owned())?;
+        let issued_at = UNIX_EPOCH + Duration::from_secs(timestamp);
+        if SystemTime::now() > issued_at + CHALLENGE_VALIDITY {
+            return Err("Challenge expired".to_owned());
+        }
+        let response = hex::decode(response).map_err(|_| "Invalid challenge response".to_owned())?;
+        let mut mac = HmacSha256::new_from_slice(token.as_bytes()).expect("HMAC accepts keys of any size");
+        mac.update(challenge.as_bytes());
+        mac.verify_slice(&response).map_err(|_| "Invalid challenge response".to_owned())?;
+        self.use_challenge(challenge, issued_at)
+    }
+
+    /// The expired challenges are forgotten, as they are refused anyway
+    fn use_challenge(&self, challenge: &str, issued_at: SystemTime) -> Result<(), String> {
+        let mut used_challenges = self.used_challenges.lock().unwrap();
+        let now = SystemTime::now();
+        used_challenges.retain(|_, issued_at| now <= *issued_at + CHALLENGE_VALIDITY);
+        if used_challenges.insert(challenge.to_owned(), issued_at).is_some() {
+            return Err("Challenge already used".to_owned());
+        }
+        Ok(())
+    }
+
+    fn sign_challenge(&self, name: &str, peer: SocketAddr, payload: &str) -> Vec<u8> {
+        let mut mac = HmacSha256::new_from_slice(&self.server_secret).expect("HMAC accepts keys of any size");
+        mac.update(format!("{}|{}|{}", name, peer, payload).as_bytes());
+        mac.finalize().into_bytes().to_vec()
+    }
+}
@@ -0,0 +1,336 @@
+use std::time::{Duration, SystemTime};
+
+use reqwest::header::{
+    HeaderMap, HeaderName, HeaderValue, AGE, CACHE_CONTROL, CONTENT_LENGTH, DATE, ETAG, EXPIRES,
+    IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, SET_COOKIE, TRANSFER_ENCODING, VARY,
+};
+use reqwest::StatusCode;
+
+use crate::proxy_response::{ProxyResponse, PROXY_CACHE_HEADER};
+
+/// Response kept by the cache along with what's needed to tell if it can still be served
+#[derive(Clone)]
+pub struct CachedResponse {
+    pub url: String,
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+    /// Values of the request headers named in `Vary`, the response is only served to the
+    /// requests having the same ones
+    pub vary: Vec<(HeaderName, Option<HeaderValue>)>,
+    /// When the target server generated the response, taking its `Age` into account
+    pub generated_at: SystemTime,
+    /// How long after being generated the response can be served without revalidation
+    pub freshness: Duration,
+}
+
+impl CachedResponse {
+    /// Returns `None` when the response must not be stored by a shared cache, or when it would
+    /// have to be revalidated every time without a way to do it. The responses setting cookies
+    /// are not stored either, as the cookies are meant for a single client
+    pub fn new(url: &str, request_headers: &HeaderMap, response: &ProxyResponse, body: Vec<u8>, now: SystemTime) -> Option<Self> {
+        if !matches!(response.status, StatusCode::OK | StatusCode::NON_AUTHORITATIVE_INFORMATION | StatusCode::NO_CONTENT) {
+            return None;
+        }
+        if response.headers.contains_key(SET_COOKIE) {
+            return None;
+        }
+        let directives = Self::directives(&response.headers);
+        if directives.iter().any(|(name, _)| name == "no-store" || name == "private") {
+            return None;
+        }
+        let vary_names = Self::vary_names(&response.headers)?;
+        let mut headers = response.headers.clone();
+        // Set again whenever the response is served
+        headers.remove(PROXY_CACHE_HEADER);
+        let mut cached = CachedResponse {
+            url: url.to_owned(),
+            status: response.status,
+            headers,
+            body,
+            vary: vary_names
+                .into_iter()
+                .map(|name| {
+                    let value = request_headers.get(&name).cloned();
+                    (name, value)
+                })
+                .collect(),
+            generated_at: now,
+            freshness: Duration::ZERO,
+        };
+        cached.refresh(now);
+        if cached.freshness.is_zero() && cached.validators().is_empty() {
+            return None;
+        }
+        Some(cached)
+    }
+
+    /// `None` when the response varies on everything, as such a response can't be matched
+    fn vary_names(headers: &HeaderMap) -> Option<Vec<HeaderName>> {
+        let mut names = Vec::new();
+        for value in headers.get_all(VARY) {
+            for name in value.to_str().unwrap_or("*").split(',').map(|n| n.trim()).filter(|n| !n.is_empty()) {
+                if name == "*" {
+                    return None;
+                }
+                names.push(HeaderName::from_bytes(name.as_bytes()).ok()?);
+            }
+        }
+        Some(names)
+    }
+
+    /// Lowercase names of the `Cache-Control` directives with their arguments
+    pub fn directives(headers: &HeaderMap) -> Vec<(String, Option<String>)> {
+        headers
+            .get_all(CACHE_CONTROL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|directive| match directive.split_once('=') {
+                Some((name, argument)) => (name.trim().to_lowercase(), Some(argument.trim().trim_matches('"').to_owned())),
+                None => (directive.trim().to_lowercase(), None),
+            })
+            .filter(|(name, _)| !name.is_empty())
+            .collect()
+    }
+
+    /// Computing the age and the freshness lifetime from the current headers, `s-maxage` taking
+    /// precedence over `max-age`, which takes precedence over `Expires`
+    fn refresh(&mut self, now: SystemTime) {
+        let date = Self::date_header(&self.headers, DATE).unwrap_or(now);
+        let apparent_age = now.duration_since(date).unwrap_or_default();
+        let age = self.headers
+            .get(AGE)
+            .and_then(|v| v.to_str().ok()?.parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or_default()
+            .max(apparent_age);
+        self.generated_at = now.checked_sub(age).unwrap_or(now);
+
+        let directives = Self::directives(&self.headers);
+        let seconds = |wanted: &str| directives
+            .iter()
+            .find(|(name, _)| name == wanted)
+            .and_then(|(_, argument)| argument.as_deref()?.parse().ok())
+            .map(Duration::from_secs);
+        self.freshness = if directives.iter().any(|(name, _)| name == "no-cache") {
+            Duration::ZERO
+        } else if let Some(lifetime) = seconds("s-maxage").or_else(|| seconds("max-age")) {
+            lifetime
+        } else if self.headers.contains_key(EXPIRES) {
+            // Invalid dates mean the response is already expired
+            Self::date_header(&self.headers, EXPIRES)
+                .and_then(|expires| expires.duration_since(date).ok())
+                .unwrap_or_default()
+        } else {
+            Duration::ZERO
+        };
+    }
+
+    fn date_header(headers: &HeaderMap, name: HeaderName) -> Option<SystemTime> {
+        httpdate::parse_http_date(headers.get(name)?.to_str().ok()?).ok()
+    }
+
+    pub fn matches(&self, request_headers: &HeaderMap) -> bool {
+        self.vary
+            .iter()
+            .all(|(name, value)| request_headers.get(name) == value.as_ref())
+    }
+
+    pub fn age(&self, now: SystemTime) -> Duration {
+        now.duration_since(self.generated_at).unwrap_or_default()
+    }
+
+    pub fn is_fresh(&self, now: SystemTime) -> bool {
+        self.age(now) < self.freshness
+    }
+
+    /// The conditional request headers for asking the target server whether the response changed
+    pub fn validators(&self) -> HeaderMap {
+        let mut validators = HeaderMap::new();
+        if let Some(etag) = self.headers.get(ETAG) {
+            validators.insert(IF_NONE_MATCH, etag.clone());
+        }
+        if let Some(last_modified) = self.headers.get(LAST_MODIFIED) {
+            validators.insert(IF_MODIFIED_SINCE, last_modified.clone());
+        }
+        validators
+    }
+
+    /// The headers of the `304 Not Modified` response are replacing the stored ones, except the
+    /// cookies, which are meant for the client revalidating only
+    pub fn revalidate(&mut self, not_modified_headers: &HeaderMap, now: SystemTime) {
+        for name in not_modified_headers.keys() {
+            if name == CONTENT_LENGTH || name == TRANSFER_ENCODING || name == SET_COOKIE {
+                continue;
+            }
+            self.headers.remove(name);
+            for value in not_modified_headers.get_all(name) {
+                self.headers.append(name, value.clone());
+            }
+        }
+        self.refresh(now);
+    }
+
+    /// Roughly the memory the entry takes
+    pub fn size(&self) -> usize {
+        let headers: usize = self.headers
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len())
+            .sum();
+        self.url.len() + headers + self.body.len()
+    }
+
+    /// The response to serve, with its current age and the cache status
+    pub fn to_response(&self, now: SystemTime, cache_status: &'static str) -> ProxyResponse {
+        let mut headers = self.headers.clone();
+        headers.insert(AGE, HeaderValue::from(self.age(now).as_secs()));
+        headers.insert(PROXY_CACHE_HEADER, HeaderValue::from_static(cache_status));
+        ProxyResponse {
+            status: self.status,
+            headers,
+            body: self.body.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(headers: &[(&str, &str)]) -> ProxyResponse {
+        let mut header_map = HeaderMap::new();
+        for (name, value) in headers {
+            header_map.append(HeaderName::from_bytes(name.as_bytes()).unwrap(), HeaderValue::from_str(value).unwrap());
+        }
+        ProxyResponse { status: StatusCode::OK, headers: header_map, body: Vec::new() }
+    }
+
+    fn cached(headers: &[(&str, &str)], now: SystemTime) -> Option<CachedResponse> {
+        CachedResponse::new("http://example.com/", &HeaderMap::new(), &response(headers), b"body".to_vec(), now)
+    }
+
+    #[test]
+    fn max_age_sets_the_freshness() {
+        let now = SystemTime::now();
+        let cached = cached(&[("cache-control", "max-age=60")], now).unwrap();
+        assert_eq!(cached.freshness, Duration::from_secs(60));
+        assert!(cached.is_fresh(now + Duration::from_secs(59)));
+        assert!(!cached.is_fresh(now + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn s_maxage_takes_precedence_over_max_age_and_expires() {
+        let now = SystemTime::now();
+        let expires = httpdate::fmt_http_date(now + Duration::from_secs(600));
+        let cached = cached(&[("cache-control", "max-age=60, s-maxage=120"), ("expires", &expires)], now).unwrap();
+        assert_eq!(cached.freshness, Duration::from_secs(120));
+    }
+
+    #[test]
+    fn expires_counts_from_the_date() {
+        let now = SystemTime::now();
+        let date = httpdate::fmt_http_date(now);
+        let expires = httpdate::fmt_http_date(now + Duration::from_secs(300));
+        let cached = cached(&[("date", &date), ("expires", &expires)], now).unwrap();
+        assert!(cached.is_fresh(now + Duration::from_secs(299)));
+        assert!(!cached.is_fresh(now + Duration::from_secs(301)));
+    }
+
+    #[test]
+    fn age_is_taken_into_account() {
+        let now = SystemTime::now();
+        let cached = cached(&[("cache-control", "max-age=60"), ("age", "50")], now).unwrap();
+        assert!(cached.is_fresh(now + Duration::from_secs(9)));
+        assert!(!cached.is_fresh(now + Duration::from_secs(11)));
+    }
+
+    #[test]
+    fn invalid_expires_means_expired() {
+        let now = SystemTime::now();
+        let cached = cached(&[("expires", "0"), ("etag", "\"v1\"")], now).unwrap();
+        assert!(!cached.is_fresh(now));
+    }
+
+    #[test]
+    fn unstorable_responses_are_refused() {
+        let now = SystemTime::now();
+        assert!(cached(&[("cache-control", "no-store, max-age=60")], now).is_none());
+        assert!(cached(&[("cache-control", "private, max-age=60")], now).is_none());
+        assert!(cached(&[("cache-control", "max-age=60"), ("set-cookie", "session=1")], now).is_none());
+        assert!(cached(&[("vary", "*"), ("cache-control", "max-age=60")], now).is_none());
+        // Neither fresh nor possible to revalidate
+        assert!(cached(&[], now).is_none());
+        assert!(cached(&[("cache-control", "no-cache")], now).is_none());
+
+        let mut not_found = response(&[("cache-control", "max-age=60")]);
+        not_found.status = StatusCode::NOT_FOUND;
+        assert!(CachedResponse::new("http://example.com/", &HeaderMap::new(), &not_found, Vec::new(), now).is_none());
+    }
+
+    #[test]
+    fn no_cache_is_stored_for_revalidation() {
+        let now = SystemTime::now();
+        let cached = cached(&[("cache-control", "no-cache, max-age=60"), ("etag", "\"v1\"")], now).unwrap();
+        assert!(!cached.is_fresh(now));
+        assert_eq!(cached.validators().get(IF_NONE_MATCH).unwrap(), "\"v1\"");
+    }
+
+    #[test]
+    fn vary_matches_the_request_headers() {
+        let now = SystemTime::now();
+        let mut request_headers = HeaderMap::new();
+        request_headers.insert("accept-language", HeaderValue::from_static("en"));
+        let response = response(&[("cache-control", "max-age=60"), ("vary", "Accept-Language, Accept-Encoding")]);
+        let cached = CachedResponse::new("http://example.com/", &request_headers, &response, Vec::new(), now).unwrap();
+
+        assert!(cached.matches(&request_headers));
+        let mut other_language = HeaderMap::new();
+        other_language.insert("accept-language", HeaderValue::from_static("de"));
+        assert!(!cached.matches(&other_language));
+        // The header missing from the original request has to be missing again
+        let mut with_encoding = request_headers.clone();
+        with_encoding.insert("accept-encoding", HeaderValue::from_static("gzip"));
+        assert!(!cached.matches(&with_encoding));
+    }
+
+    #[test]
+    fn validators_come_from_etag_and_last_modified() {
+        let now = SystemTime::now();
+        let last_modified = httpdate::fmt_http_date(now - Duration::from_secs(3600));
+        let cached = cached(&[("etag", "\"v1\""), ("last-modified", &last_modified)], now).unwrap();
+        let validators = cached.validators();
+        assert_eq!(validators.get(IF_NONE_MATCH).unwrap(), "\"v1\"");
+        assert_eq!(validators.get(IF_MODIFIED_SINCE).unwrap(), last_modified.as_str());
+    }
+
+    #[test]
+    fn revalidation_replaces_the_headers_and_refreshes() {
+        let now = SystemTime::now();
+        let mut cached = cached(&[("etag", "\"v1\""), ("content-length", "4"), ("x-kept", "1")], now).unwrap();
+        assert!(!cached.is_fresh(now));
+
+        let later = now + Duration::from_secs(100);
+        let not_modified = response(&[
+            ("cache-control", "max-age=60"),
+            ("etag", "\"v2\""),
+            ("content-length", "0"),
+            ("set-cookie", "session=1"),
+        ]);
+        cached.revalidate(&not_modified.headers, later);
+
+        assert!(cached.is_fresh(later));
+        assert_eq!(cached.headers.get(ETAG).unwrap(), "\"v2\"");
+        assert_eq!(cached.headers.get(CONTENT_LENGTH).unwrap(), "4");
+        assert_eq!(cached.headers.get("x-kept").unwrap(), "1");
+        assert!(!cached.headers.contains_key(SET_COOKIE));
+    }
+
+    #[test]
+    fn proxy_cache_header_isnt_stored() {
+        let now = SystemTime::now();
+        let cached = cached(&[("cache-control", "max-age=60"), (PROXY_CACHE_HEADER, "MISS")], now).unwrap();
+        assert!(!cached.headers.contains_key(PROXY_CACHE_HEADER));
+        assert_eq!(cached.to_response(now, "HIT").headers.get(PROXY_CACHE_HEADER).unwrap(), "HIT");
+    }
+}
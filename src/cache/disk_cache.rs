@@ -0,0 +1,309 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use reqwest::header::HeaderMap;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncWriteExt;
+use tokio::time::sleep;
+
+use super::cached_response::CachedResponse;
+use super::disk_cache_entry::DiskCacheEntry;
+
+const INDEX_FILE: &str = "index.json";
+const BODIES_DIRECTORY: &str = "bodies";
+/// The changes of the index are written at most this often
+const INDEX_SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Responses kept across the restarts. The bodies are stored in files named by their hash, so
+/// the same body is stored once, and the metadata of all the responses is in a single index
+/// file. The size quota counts the bodies, and the least recently used responses are evicted first.
+/// The index is kept in memory and saved in the background, so a crash loses the changes of the
+/// last few seconds, which are reconciled with the bodies on the next start
+pub struct DiskCache {
+    directory: PathBuf,
+    max_size: u64,
+    /// Only held while the index changes, never while the files are read or written
+    index: Arc<Mutex<DiskIndex>>,
+}
+
+/// The entries are keyed by their `last_used`, so the first one is always the least recently used
+#[derive(Default)]
+struct DiskIndex {
+    entries: BTreeMap<u64, DiskCacheEntry>,
+    /// Every variant of the URL, as the responses can vary on the request headers
+    by_url: HashMap<String, Vec<u64>>,
+    /// Size of every stored body and the number of the entries referring to it
+    bodies: HashMap<String, (u64, usize)>,
+    /// The bodies shared by several entries are counted once
+    size: u64,
+    next_use: u64,
+    /// Changed since it was last saved
+    dirty: bool,
+}
+
+/// Content of the index file
+#[derive(Serialize, Deserialize, Default)]
+struct IndexFile {
+    entries: Vec<DiskCacheEntry>,
+    /// Counter for the `last_used` of the entries
+    next_use: u64,
+}
+
+impl DiskCache {
+    /// Loading the index left by the previous run, dropping the entries which bodies are missing
+    /// and the bodies no entry refers to
+    pub fn open(directory: &Path, max_size: u64) -> Result<Self, String> {
+        let bodies = directory.join(BODIES_DIRECTORY);
+        std::fs::create_dir_all(&bodies)
+            .map_err(|e| format!("Failed creating the cache directory {}: {}", bodies.display(), e))?;
+        let file: IndexFile = match std::fs::read(directory.join(INDEX_FILE)) {
+            Ok(content) => serde_json::from_slice(&content).unwrap_or_else(|e| {
+                println!("Ignoring the corrupted cache index in {}: {}", directory.display(), e);
+                IndexFile::default()
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => IndexFile::default(),
+            Err(e) => return Err(format!("Failed reading the cache index in {}: {}", directory.display(), e)),
+        };
+        let mut index = DiskIndex { next_use: file.next_use, ..DiskIndex::default() };
+        for entry in file.entries {
+            if bodies.join(&entry.body_hash).is_file() {
+                index.insert(entry);
+            }
+        }
+        let evicted = index.shrink_to(max_size);
+        index.unused_bodies(evicted);
+        let files = std::fs::read_dir(&bodies)
+            .map_err(|e| format!("Failed listing the cache directory {}: {}", bodies.display(), e))?;
+        for file in files.flatten() {
+            if !index.bodies.contains_key(&*file.file_name().to_string_lossy()) {
+                let _ = std::fs::remove_file(file.path());
+            }
+        }
+        std::fs::write(directory.join(INDEX_FILE), index.serialized()?)
+            .map_err(|e| format!("Failed writing the cache index in {}: {}", directory.display(), e))?;
+        index.dirty = false;
+        println!("Loaded {} cached responses from {}", index.entries.len(), directory.display());
+        Ok(DiskCache { directory: directory.to_owned(), max_size, index: Arc::new(Mutex::new(index)) })
+    }
+
+    /// Saving the changed index once per interval, instead of on every change
+    pub fn start_loop(&self) {
+        let index = self.index.clone();
+        let path = self.directory.join(INDEX_FILE);
+        tokio::spawn(async move {
+            loop {
+                sleep(INDEX_SAVE_INTERVAL).await;
+                let content = {
+                    let mut index = index.lock().unwrap();
+                    if !index.dirty {
+                        continue;
+                    }
+                    index.dirty = false;
+                    index.serialized()
+                };
+                let result = match content {
+                    Ok(content) => Self::write_atomically(&path, &content).await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = result {
+                    println!("Failed saving the cache index: {}", e);
+                    index.lock().unwrap().dirty = true;
+                }
+            }
+        });
+    }
+
+    /// The body is checked against its hash, the entries with missing or corrupted bodies are
+    /// dropped
+    pub async fn load(&self, url: &str, request_headers: &HeaderMap) -> Option<CachedResponse> {
+        let (key, entry) = {
+            let index = self.index.lock().unwrap();
+            let key = index.find(url, request_headers)?;
+            (key, index.entries[&key].clone())
+        };
+        let body = tokio::fs::read(self.body_path(&entry.body_hash)).await.unwrap_or_default();
+        let cached = if Self::hash(&body) == entry.body_hash { entry.to_cached(body) } else { None };
+        let unused = {
+            let mut index = self.index.lock().unwrap();
+            if cached.is_some() {
+                // The entry could have been replaced or evicted while the body was read
+                if index.entries.contains_key(&key) {
+                    index.touch(key);
+                }
+                return cached;
+            }
+            println!("Dropping the corrupted cached response of {}", url);
+            index.remove(key);
+            index.unused_bodies(vec![entry.body_hash])
+        };
+        self.remove_bodies(unused).await;
+        None
+    }
+
+    /// Replacing the previous variant of the response, the body is only written if it's not
+    /// stored yet
+    pub async fn store(&self, cached: &CachedResponse, request_headers: &HeaderMap) -> Result<(), String> {
+        if cached.body.len() as u64 > self.max_size {
+            return Ok(());
+        }
+        let body_hash = Self::hash(&cached.body);
+        let body_path = self.body_path(&body_hash);
+        // A body removed by a concurrent eviction right after this check is only a miss later, as
+        // the bodies are checked on load
+        if !body_path.is_file() {
+            Self::write_atomically(&body_path, &cached.body).await?;
+        }
+        let unused = {
+            let mut index = self.index.lock().unwrap();
+            // The replaced body is removed only after the new entry is added, as they can be the same
+            let mut replaced = Vec::new();
+            if let Some(key) = index.find(&cached.url, request_headers) {
+                replaced.extend(index.remove(key).map(|e| e.body_hash));
+            }
+            let last_used = index.next_use();
+            index.insert(DiskCacheEntry::new(cached, body_hash, last_used));
+            replaced.extend(index.shrink_to(self.max_size));
+            index.unused_bodies(replaced)
+        };
+        self.remove_bodies(unused).await;
+        Ok(())
+    }
+
+    /// The unsafe requests are making the stored responses of their URL obsolete
+    pub async fn invalidate(&self, url: &str) {
+        let unused = {
+            let mut index = self.index.lock().unwrap();
+            let removed: Vec<String> = index.by_url
+                .get(url)
+                .cloned()
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|key| index.remove(key).map(|e| e.body_hash))
+                .collect();
+            index.unused_bodies(removed)
+        };
+        self.remove_bodies(unused).await;
+    }
+
+    async fn remove_bodies(&self, body_hashes: Vec<String>) {
+        for body_hash in body_hashes {
+            if let Err(e) = tokio::fs::remove_file(self.body_path(&body_hash)).await {
+                println!("Failed removing the cached body {}: {}", body_hash, e);
+            }
+        }
+    }
+
+    fn body_path(&self, body_hash: &str) -> PathBuf {
+        self.directory.join(BODIES_DIRECTORY).join(body_hash)
+    }
+
+    fn hash(body: &[u8]) -> String {
+        hex::encode(Sha256::digest(body))
+    }
+
+    /// Writing next to the file, syncing it and renaming, so that a crash never leaves it half
+    /// written
+    async fn write_atomically(path: &Path, content: &[u8]) -> Result<(), String> {
+        let temporary = path.with_extension("tmp");
+        let failure = |e: std::io::Error| format!("Failed writing {}: {}", temporary.display(), e);
+        let mut file = tokio::fs::File::create(&temporary).await.map_err(failure)?;
+        file.write_all(content).await.map_err(failure)?;
+        file.sync_all().await.map_err(failure)?;
+        tokio::fs::rename(&temporary, path)
+            .await
+            .map_err(|e| format!("Failed replacing {}: {}", path.display(), e))
+    }
+}
+
+impl DiskIndex {
+    fn find(&self, url: &str, request_headers: &HeaderMap) -> Option<u64> {
+        self.by_url
+            .get(url)?
+            .iter()
+            .copied()
+            .find(|key| self.entries[key].matches(request_headers))
+    }
+
+    fn next_use(&mut self) -> u64 {
+        self.next_use += 1;
+        self.next_use
+    }
+
+    /// Moving the entry to the most recently used end
+    fn touch(&mut self, key: u64) {
+        let mut entry = self.remove(key).expect("Touched entries exist");
+        entry.last_used = self.next_use();
+        self.insert(entry);
+    }
+
+    /// Evicting the least recently used entries until the bodies fit the quota, returns the
+    /// hashes of the evicted bodies
+    fn shrink_to(&mut self, max_size: u64) -> Vec<String> {
+        let mut evicted = Vec::new();
+        while self.size > max_size {
+            let oldest = *self.entries.keys().next().expect("The size is counted from the entries");
+            evicted.extend(self.remove(oldest).map(|e| e.body_hash));
+        }
+        evicted
+    }
+
+    /// The entries read from an older index can share their `last_used`, the later ones are
+    /// moved past the others
+    fn insert(&mut self, mut entry: DiskCacheEntry) {
+        if self.entries.contains_key(&entry.last_used) {
+            entry.last_used = self.next_use();
+        }
+        self.next_use = self.next_use.max(entry.last_used);
+        let (_, references) = self.bodies.entry(entry.body_hash.clone()).or_insert((entry.body_size, 0));
+        if *references == 0 {
+            self.size += entry.body_size;
+        }
+        *references += 1;
+        self.by_url.entry(entry.url.clone()).or_default().push(entry.last_used);
+        self.entries.insert(entry.last_used, entry);
+        self.dirty = true;
+    }
+
+    /// The body stays counted until `unused_bodies` finds it's not referred to anymore
+    fn remove(&mut self, key: u64) -> Option<DiskCacheEntry> {
+        let entry = self.entries.remove(&key)?;
+        if let Some(keys) = self.by_url.get_mut(&entry.url) {
+            keys.retain(|k| *k != key);
+            if keys.is_empty() {
+                self.by_url.remove(&entry.url);
+            }
+        }
+        if let Some((size, references)) = self.bodies.get_mut(&entry.body_hash) {
+            *references -= 1;
+            if *references == 0 {
+                self.size -= *size;
+            }
+        }
+        self.dirty = true;
+        Some(entry)
+    }
+
+    /// Forgetting the bodies no entry refers to anymore, returns their hashes so the files can be
+    /// removed
+    fn unused_bodies(&mut self, body_hashes: Vec<String>) -> Vec<String> {
+        let unique: HashSet<String> = body_hashes.into_iter().collect();
+        unique
+            .into_iter()
+            .filter(|body_hash| match self.bodies.get(body_hash) {
+                Some((_, 0)) => {
+                    self.bodies.remove(body_hash);
+                    true
+                }
+                _ => false,
+            })
+            .collect()
+    }
+
+    fn serialized(&self) -> Result<Vec<u8>, String> {
+        let file = IndexFile { entries: self.entries.values().cloned().collect(), next_use: self.next_use };
+        serde_json::to_vec(&file).map_err(|e| format!("Failed serializing the cache index: {}", e))
+    }
+}
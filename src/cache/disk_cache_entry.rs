@@ -0,0 +1,85 @@
+use std::time::{Duration, UNIX_EPOCH};
+
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+
+use super::cached_response::CachedResponse;
+
+/// Metadata of a response kept on disk, the body is in a separate file named by its hash.
+/// The header values are hex encoded, as they don't have to be valid UTF-8
+#[derive(Clone, Serialize, Deserialize)]
+pub struct DiskCacheEntry {
+    pub url: String,
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub vary: Vec<(String, Option<String>)>,
+    /// Seconds since the Unix epoch
+    pub generated_at: u64,
+    pub freshness_secs: u64,
+    /// Hex encoded SHA-256 of the body
+    pub body_hash: String,
+    pub body_size: u64,
+    /// Bigger for the more recently used entries
+    pub last_used: u64,
+}
+
+impl DiskCacheEntry {
+    pub fn new(cached: &CachedResponse, body_hash: String, last_used: u64) -> Self {
+        DiskCacheEntry {
+            url: cached.url.clone(),
+            status: cached.status.as_u16(),
+            headers: cached.headers
+                .iter()
+                .map(|(name, value)| (name.as_str().to_owned(), hex::encode(value.as_bytes())))
+                .collect(),
+            vary: cached.vary
+                .iter()
+                .map(|(name, value)| (name.as_str().to_owned(), value.as_ref().map(|v| hex::encode(v.as_bytes()))))
+                .collect(),
+            generated_at: cached.generated_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            freshness_secs: cached.freshness.as_secs(),
+            body_hash,
+            body_size: cached.body.len() as u64,
+            last_used,
+        }
+    }
+
+    /// Returns `None` if the stored metadata is not valid anymore
+    pub fn to_cached(&self, body: Vec<u8>) -> Option<CachedResponse> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in self.headers.iter() {
+            headers.append(HeaderName::from_bytes(name.as_bytes()).ok()?, Self::decode_value(value)?);
+        }
+        let mut vary = Vec::new();
+        for (name, value) in self.vary.iter() {
+            let value = match value {
+                Some(value) => Some(Self::decode_value(value)?),
+                None => None,
+            };
+            vary.push((HeaderName::from_bytes(name.as_bytes()).ok()?, value));
+        }
+        Some(CachedResponse {
+            url: self.url.clone(),
+            status: StatusCode::from_u16(self.status).ok()?,
+            headers,
+            body,
+            vary,
+            generated_at: UNIX_EPOCH + Duration::from_secs(self.generated_at),
+            freshness: Duration::from_secs(self.freshness_secs),
+        })
+    }
+
+    pub fn matches(&self, request_headers: &HeaderMap) -> bool {
+        self.vary.iter().all(|(name, value)| {
+            request_headers.get(name.as_str()).map(|v| hex::encode(v.as_bytes())) == *value
+        })
+    }
+
+    fn decode_value(value: &str) -> Option<HeaderValue> {
+        HeaderValue::from_bytes(&hex::decode(value).ok()?).ok()
+    }
+}
@@ -0,0 +1,4 @@
+pub mod cached_response;
+pub mod disk_cache;
+pub mod disk_cache_entry;
+pub mod response_cache;
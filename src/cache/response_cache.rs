@@ -0,0 +1,243 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use reqwest::header::{HeaderMap, AUTHORIZATION, IF_MATCH, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE, IF_UNMODIFIED_SINCE, PRAGMA, RANGE};
+use reqwest::Method;
+use tokio::sync::Mutex;
+
+use crate::config::CacheConfig;
+use crate::proxy_request::ProxyRequest;
+use crate::proxy_response::ProxyResponse;
+
+use super::cached_response::CachedResponse;
+use super::disk_cache::DiskCache;
+
+/// Values of the cache status header in the response envelope
+pub const CACHE_HIT: &str = "HIT";
+pub const CACHE_REVALIDATED: &str = "REVALIDATED";
+pub const CACHE_MISS: &str = "MISS";
+pub const CACHE_BYPASS: &str = "BYPASS";
+
+pub enum CacheLookup {
+    /// Can be served as is
+    Fresh(ProxyResponse),
+    /// Has to be confirmed by the target server first, with these conditional headers
+    Stale(HeaderMap),
+    Miss,
+}
+
+/// Responses shared between all the clients, bounded by their overall size. The least recently
+/// used ones are evicted first. With the persistent tier, the responses are also kept on disk and
+/// the ones missing in memory are looked up there before going to the target server
+pub struct ResponseCache {
+    /// Never held during the disk work, so the memory hits don't wait for the disk
+    state: Mutex<CacheState>,
+    /// Shared with the tasks writing to it in the background
+    disk: Option<Arc<DiskCache>>,
+    max_size: usize,
+    max_entry_size: usize,
+}
+
+/// The entries are keyed by a counter which is renewed on every use, so the first one is always
+/// the least recently used
+#[derive(Default)]
+struct CacheState {
+    entries: BTreeMap<u64, CachedResponse>,
+    /// Every variant of the URL, as the responses can vary on the request headers
+    by_url: HashMap<String, Vec<u64>>,
+    next_key: u64,
+    size: usize,
+}
+
+impl ResponseCache {
+    pub fn new(config: &CacheConfig) -> Result<Self, String> {
+        let disk = config.disk_path
+            .as_ref()
+            .map(|path| DiskCache::open(path, config.disk_max_size).map(Arc::new))
+            .transpose()?;
+        if let Some(disk) = &disk {
+            disk.start_loop();
+        }
+        Ok(ResponseCache {
+            state: Mutex::new(CacheState::default()),
+            disk,
+            max_size: config.max_size,
+            max_entry_size: config.max_entry_size,
+        })
+    }
+
+    /// The cache is disabled with zero size and no persistent tier
+    pub fn is_enabled(&self) -> bool {
+        self.max_size > 0 || self.disk.is_some()
+    }
+
+    pub fn max_entry_size(&self) -> usize {
+        self.max_entry_size
+    }
+
+    /// Only the plain GET requests are served from the cache. The requests with credentials or
+    /// their own conditions are passed to the target server as is
+    pub fn is_cacheable(&self, request: &ProxyRequest) -> bool {
+        self.is_enabled()
+            && request.method == Method::GET
+            && request.body.as_ref().is_none_or(|b| b.is_empty())
+            && ![AUTHORIZATION, IF_MATCH, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE, IF_UNMODIFIED_SINCE, RANGE]
+                .iter()
+                .any(|name| request.headers.contains_key(name))
+            && !CachedResponse::directives(&request.headers)
+                .iter()
+                .any(|(name, _)| name == "no-store")
+    }
+
+    /// The client can ask for the revalidation with `no-cache` or `max-age=0`
+    pub async fn lookup(&self, url: &str, request_headers: &HeaderMap) -> CacheLookup {
+        let now = SystemTime::now();
+        let revalidation_asked = request_headers.get(PRAGMA).is_some_and(|v| v == "no-cache")
+            || CachedResponse::directives(request_headers)
+                .iter()
+                .any(|(name, argument)| name == "no-cache" || (name == "max-age" && argument.as_deref() == Some("0")));
+        {
+            let mut state = self.state.lock().await;
+            if let Some(key) = state.find(url, request_headers) {
+                let key = state.touch(key);
+                return Self::lookup_result(&state.entries[&key], now, revalidation_asked);
+            }
+        }
+        let cached = match self.load_from_disk(url, request_headers).await {
+            Some(cached) => cached,
+            None => return CacheLookup::Miss,
+        };
+        let result = Self::lookup_result(&cached, now, revalidation_asked);
+        self.remember(&mut *self.state.lock().await, request_headers, cached);
+        result
+    }
+
+    fn lookup_result(cached: &CachedResponse, now: SystemTime, revalidation_asked: bool) -> CacheLookup {
+        if cached.is_fresh(now) && !revalidation_asked {
+            return CacheLookup::Fresh(cached.to_response(now, CACHE_HIT));
+        }
+        match cached.validators() {
+            validators if validators.is_empty() => CacheLookup::Miss,
+            validators => CacheLookup::Stale(validators),
+        }
+    }
+
+    /// Storing the response if it's allowed and fits, replacing the previous variant
+    pub async fn store(&self, url: &str, request_headers: &HeaderMap, response: &ProxyResponse, body: Vec<u8>) {
+        let cached = match CachedResponse::new(url, request_headers, response, body, SystemTime::now()) {
+            Some(cached) => cached,
+            None => return,
+        };
+        if cached.size() > self.max_entry_size {
+            return;
+        }
+        self.persist(&cached, request_headers);
+        self.remember(&mut *self.state.lock().await, request_headers, cached);
+    }
+
+    /// Serving the stored response after the target server confirmed it didn't change. Returns
+    /// `None` if the response was evicted in the meantime
+    pub async fn revalidate(&self, url: &str, request_headers: &HeaderMap, not_modified_headers: &HeaderMap) -> Option<ProxyResponse> {
+        let now = SystemTime::now();
+        let in_memory = {
+            let mut state = self.state.lock().await;
+            state.find(url, request_headers).and_then(|key| state.remove(key))
+        };
+        let mut cached = match in_memory {
+            Some(cached) => cached,
+            None => self.load_from_disk(url, request_headers).await?,
+        };
+        cached.revalidate(not_modified_headers, now);
+        let response = cached.to_response(now, CACHE_REVALIDATED);
+        self.persist(&cached, request_headers);
+        self.remember(&mut *self.state.lock().await, request_headers, cached);
+        Some(response)
+    }
+
+    /// The unsafe requests are making the stored responses of their URL obsolete
+    pub async fn invalidate(&self, url: &str) {
+        {
+            let mut state = self.state.lock().await;
+            for key in state.by_url.get(url).cloned().unwrap_or_default() {
+                state.remove(key);
+            }
+        }
+        if let Some(disk) = &self.disk {
+            disk.invalidate(url).await;
+        }
+    }
+
+    /// Keeping the response in memory if it fits, replacing the previous variant
+    fn remember(&self, state: &mut CacheState, request_headers: &HeaderMap, cached: CachedResponse) {
+        if let Some(key) = state.find(&cached.url, request_headers) {
+            state.remove(key);
+        }
+        if cached.size() <= self.max_size {
+            state.insert(cached);
+            state.shrink_to(self.max_size);
+        }
+    }
+
+    /// Written in the background, so that the clients don't wait for the disk. The persistent
+    /// tier failures are only logged, as the response can still be served
+    fn persist(&self, cached: &CachedResponse, request_headers: &HeaderMap) {
+        if let Some(disk) = &self.disk {
+            let (disk, cached, request_headers) = (disk.clone(), cached.clone(), request_headers.clone());
+            tokio::spawn(async move {
+                if let Err(e) = disk.store(&cached, &request_headers).await {
+                    println!("Failed persisting the cached response of {}: {}", cached.url, e);
+                }
+            });
+        }
+    }
+
+    async fn load_from_disk(&self, url: &str, request_headers: &HeaderMap) -> Option<CachedResponse> {
+        self.disk.as_ref()?.load(url, request_headers).await
+    }
+}
+
+impl CacheState {
+    fn find(&self, url: &str, request_headers: &HeaderMap) -> Option<u64> {
+        self.by_url
+            .get(url)?
+            .iter()
+            .copied()
+            .find(|key| self.entries[key].matches(request_headers))
+    }
+
+    /// Moving the entry to the most recently used end, returns its new key
+    fn touch(&mut self, key: u64) -> u64 {
+        let cached = self.remove(key).expect("Touched entries exist");
+        self.insert(cached)
+    }
+
+    /// Evicting the least recently used entries until the overall size fits
+    fn shrink_to(&mut self, max_size: usize) {
+        while self.size > max_size {
+            let oldest = *self.entries.keys().next().expect("The size is counted from the entries");
+            self.remove(oldest);
+        }
+    }
+
+    fn insert(&mut self, cached: CachedResponse) -> u64 {
+        let key = self.next_key;
+        self.next_key += 1;
+        self.size += cached.size();
+        self.by_url.entry(cached.url.clone()).or_default().push(key);
+        self.entries.insert(key, cached);
+        key
+    }
+
+    fn remove(&mut self, key: u64) -> Option<CachedResponse> {
+        let cached = self.entries.remove(&key)?;
+        self.size -= cached.size();
+        if let Some(keys) = self.by_url.get_mut(&cached.url) {
+            keys.retain(|k| *k != key);
+            if keys.is_empty() {
+                self.by_url.remove(&cached.url);
+            }
+        }
+        Some(cached)
+    }
+}
@@ -0,0 +1,114 @@
+use std::io::Write;
+
+use crate::handshake::SessionParameters;
+
+/// Name of the capability, its argument is the list of the algorithms separated by `+` from the
+/// client, and the chosen one from the server
+pub const COMPRESSION_CAPABILITY: &str = "compression";
+/// Anything smaller is sent as is, as the compression wouldn't win anything
+const MIN_COMPRESSED_SIZE: usize = 64;
+/// Anything bigger is compressed on the blocking threads, so that it doesn't hold up the other
+/// tasks of the runtime
+const BLOCKING_COMPRESSION_SIZE: usize = 32 * 1024;
+const ZSTD_LEVEL: i32 = 3;
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW: u32 = 22;
+
+/// Compression of the responses agreed for a session. The content is compressed before being
+/// split into batches or frames, and flagged in their headers, so that the content which
+/// doesn't get smaller can still go as is
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Zstd,
+    Brotli,
+    Gzip,
+}
+
+/// In the order the server prefers them
+const ALGORITHMS: &[Compression] = &[Compression::Zstd, Compression::Brotli, Compression::Gzip];
+
+impl Compression {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Compression::Zstd => "zstd",
+            Compression::Brotli => "br",
+            Compression::Gzip => "gzip",
+        }
+    }
+
+    /// Choosing the algorithm for the session if the client asked for the compression, and telling
+    /// the client about it in the accept response. The capability is dropped when there is no
+    /// algorithm both sides support
+    pub fn negotiate(parameters: &mut SessionParameters) -> Option<Self> {
+        if !parameters.has_capability(COMPRESSION_CAPABILITY) {
+            return None;
+        }
+        let offered: Vec<&str> = parameters
+            .client_arguments
+            .get(COMPRESSION_CAPABILITY)
+            .map(|a| a.split('+').map(|n| n.trim()).collect())
+            .unwrap_or_default();
+        let chosen = ALGORITHMS.iter().copied().find(|a| offered.contains(&a.name()));
+        match chosen {
+            Some(algorithm) => {
+                parameters
+                    .server_arguments
+                    .insert(COMPRESSION_CAPABILITY.to_owned(), algorithm.name().to_owned());
+            }
+            None => parameters.capabilities.retain(|c| c != COMPRESSION_CAPABILITY),
+        }
+        chosen
+    }
+
+    /// The algorithm the session agreed on, if any
+    pub fn of_session(parameters: &SessionParameters) -> Option<Self> {
+        let name = parameters.server_arguments.get(COMPRESSION_CAPABILITY)?;
+        ALGORITHMS.iter().copied().find(|a| a.name() == name)
+    }
+
+    /// Returns the compressed content only if it's smaller than the original
+    fn compress_if_smaller(&self, content: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        if content.len() < MIN_COMPRESSED_SIZE {
+            return Ok(None);
+        }
+        let compressed = self.compress(content)?;
+        Ok(if compressed.len() < content.len() { Some(compressed) } else { None })
+    }
+
+    /// Returns the content to send and whether it's compressed
+    pub async fn compress_content(self, content: Vec<u8>) -> Result<(Vec<u8>, bool), String> {
+        if content.len() < BLOCKING_COMPRESSION_SIZE {
+            return self.compress_owned(content);
+        }
+        tokio::task::spawn_blocking(move || self.compress_owned(content))
+            .await
+            .map_err(|e| format!("Compression task failed: {}", e))?
+    }
+
+    fn compress_owned(&self, content: Vec<u8>) -> Result<(Vec<u8>, bool), String> {
+        Ok(match self.compress_if_smaller(&content)? {
+            Some(compressed) => (compressed, true),
+            None => (content, false),
+        })
+    }
+
+    fn compress(&self, content: &[u8]) -> Result<Vec<u8>, String> {
+        let failure = |e: std::io::Error| format!("Failed compressing with {}: {}", self.name(), e);
+        match self {
+            Compression::Zstd => zstd::bulk::compress(content, ZSTD_LEVEL).map_err(failure),
+            Compression::Brotli => {
+                let mut compressed = Vec::new();
+                {
+                    let mut writer = brotli::CompressorWriter::new(&mut compressed, 4096, BROTLI_QUALITY, BROTLI_WINDOW);
+                    writer.write_all(content).map_err(failure)?;
+                }
+                Ok(compressed)
+            }
+            Compression::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(content).map_err(failure)?;
+                encoder.finish().map_err(failure)
+            }
+        }
+    }
+}
@@ -0,0 +1,567 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::Parser;
+use ipnet::IpNet;
+use reqwest::header::{HeaderName, HeaderValue};
+use serde::Deserialize;
+
+use crate::udp::custom_protocol_processor::HEADERS_BYTES_COUNT;
+use crate::udp::udp_session_cipher::ENCRYPTION_OVERHEAD;
+
+/// The biggest payload of an IPv4 UDP datagram
+const MAX_UDP_PAYLOAD_SIZE: usize = 65507;
+
+#[derive(Parser)]
+#[command(about = "Proxy server speaking the custom protocol over TCP and UDP")]
+struct Cli {
+    /// Path to the TOML config file, the flags below are overriding its values
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+    /// Address of the TCP listener
+    #[arg(long)]
+    tcp_bind: Option<SocketAddr>,
+    /// Maximum size of a TCP request message in bytes
+    #[arg(long)]
+    tcp_max_message_size: Option<usize>,
+    /// Seconds after which an idle TCP session is closed
+    #[arg(long)]
+    tcp_idle_timeout_secs: Option<u64>,
+    /// Maximum size of a TCP response in bytes when the session didn't agree on the streaming
+    #[arg(long)]
+    tcp_max_response_size: Option<usize>,
+    /// PEM certificate chain of the TCP listener, enables TLS together with the key, either of
+    /// them can come from the config file
+    #[arg(long)]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key of the TCP listener
+    #[arg(long)]
+    tls_key: Option<PathBuf>,
+    /// PEM certificates of the CA the TLS clients have to present certificates signed by
+    #[arg(long)]
+    tls_client_ca: Option<PathBuf>,
+    /// Address of the HTTP proxy listener, which is disabled unless given
+    #[arg(long)]
+    http_bind: Option<SocketAddr>,
+    /// Maximum size of a request body sent through the HTTP proxy listener in bytes
+    #[arg(long)]
+    http_max_body_size: Option<usize>,
+    /// Seconds an HTTP proxy client has to send the request headers in, and a tunnel can stay idle
+    #[arg(long)]
+    http_idle_timeout_secs: Option<u64>,
+    /// Maximum number of the HTTP proxy connections served at the same time
+    #[arg(long)]
+    http_max_connections: Option<usize>,
+    /// Address of the SOCKS5 listener, which is disabled unless given
+    #[arg(long)]
+    socks_bind: Option<SocketAddr>,
+    /// Seconds a SOCKS5 client has to complete the handshake in
+    #[arg(long)]
+    socks_handshake_timeout_secs: Option<u64>,
+    /// Seconds a SOCKS5 tunnel or UDP relay can stay idle
+    #[arg(long)]
+    socks_idle_timeout_secs: Option<u64>,
+    /// Maximum number of the SOCKS5 connections served at the same time
+    #[arg(long)]
+    socks_max_connections: Option<usize>,
+    /// Address of the UDP socket
+    #[arg(long)]
+    udp_bind: Option<SocketAddr>,
+    /// Maximum size of a UDP request datagram in bytes
+    #[arg(long)]
+    udp_max_message_size: Option<usize>,
+    /// Size of a UDP response batch in bytes, including the headers
+    #[arg(long)]
+    udp_batch_size: Option<usize>,
+    /// Capacity of the UDP requests and responses queues
+    #[arg(long)]
+    udp_channel_capacity: Option<usize>,
+    /// Seconds the sent UDP batches are kept for repeat requests
+    #[arg(long)]
+    udp_cache_ttl_secs: Option<u64>,
+    /// Replace the body of non-successful upstream responses with a generated HTML page
+    #[arg(long)]
+    html_error_pages: bool,
+    /// Header to remove from the forwarded requests and the responses, can be repeated
+    #[arg(long = "strip-header", value_name = "NAME")]
+    strip_headers: Vec<String>,
+    /// Header to add to the forwarded requests in `Name: value` format, can be repeated
+    #[arg(long = "inject-header", value_name = "HEADER", value_parser = parse_header_argument)]
+    inject_headers: Vec<(String, String)>,
+    /// Network the proxy refuses to connect to, in CIDR notation, can be repeated
+    #[arg(long = "deny-network", value_name = "CIDR")]
+    denied_networks: Vec<IpNet>,
+    /// Network the proxy connects to even if it's denied, in CIDR notation, can be repeated
+    #[arg(long = "allow-network", value_name = "CIDR")]
+    allowed_networks: Vec<IpNet>,
+    /// URL scheme the proxy fetches, can be repeated
+    #[arg(long = "allow-scheme", value_name = "SCHEME")]
+    allowed_schemes: Vec<String>,
+    /// Host pattern the proxy fetches, like `example.com` or `*.example.com`, can be repeated
+    #[arg(long = "allow-host", value_name = "PATTERN")]
+    allowed_hosts: Vec<String>,
+    /// Host pattern the proxy refuses, like `example.com` or `*.example.com`, can be repeated
+    #[arg(long = "deny-host", value_name = "PATTERN")]
+    denied_hosts: Vec<String>,
+    /// Target port the proxy connects to, can be repeated
+    #[arg(long = "allow-port", value_name = "PORT")]
+    allowed_ports: Vec<u16>,
+    /// Overall size of the cached responses in bytes, 0 disables the cache
+    #[arg(long)]
+    cache_max_size: Option<usize>,
+    /// Size of the biggest response the cache keeps in bytes
+    #[arg(long)]
+    cache_max_entry_size: Option<usize>,
+    /// Directory of the persistent cache, which is disabled unless given
+    #[arg(long)]
+    cache_dir: Option<PathBuf>,
+    /// Overall size of the cached bodies on disk in bytes
+    #[arg(long)]
+    cache_disk_max_size: Option<u64>,
+    /// Maximum length of the requested URL
+    #[arg(long)]
+    max_url_length: Option<usize>,
+    /// Client allowed to connect in `name:token` format, can be repeated. Enables the authentication
+    #[arg(long = "auth-token", value_name = "NAME:TOKEN", value_parser = parse_token_argument)]
+    auth_tokens: Vec<(String, String)>,
+    /// Seconds after which a silent UDP client has to connect again
+    #[arg(long)]
+    udp_session_ttl_secs: Option<u64>,
+    /// Refuse the UDP clients which don't agree on the encryption during the greeting
+    #[arg(long)]
+    udp_require_encryption: bool,
+    /// How many times the received bytes can be sent to a UDP address before it's validated, 0 (the default) disables the limit
+    #[arg(long)]
+    udp_amplification_factor: Option<usize>,
+}
+
+fn parse_header_argument(argument: &str) -> Result<(String, String), String> {
+    argument
+        .split_once(':')
+        .map(|(name, value)| (name.trim().to_owned(), value.trim().to_owned()))
+        .ok_or_else(|| "Expected `Name: value` format".to_owned())
+}
+
+fn parse_token_argument(argument: &str) -> Result<(String, String), String> {
+    argument
+        .split_once(':')
+        .filter(|(name, token)| !name.is_empty() && !token.is_empty())
+        .map(|(name, token)| (name.to_owned(), token.to_owned()))
+        .ok_or_else(|| "Expected `name:token` format".to_owned())
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub tcp: TcpConfig,
+    pub udp: UdpConfig,
+    pub http: HttpConfig,
+    pub socks: SocksConfig,
+    pub proxy: ProxyConfig,
+    pub policy: PolicyConfig,
+    pub cache: CacheConfig,
+    pub auth: AuthConfig,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TcpConfig {
+    pub bind: SocketAddr,
+    pub max_message_size: usize,
+    /// The session is closed if the client doesn't send anything during this period while having
+    /// no requests in flight
+    pub idle_timeout_secs: u64,
+    /// Without the streaming, the responses are read into memory, so the bigger ones are refused
+    pub max_response_size: usize,
+    /// The listener speaks plain TCP when missing
+    pub tls: Option<TlsConfig>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    /// The client certificates are verified against this CA when given
+    pub client_ca_path: Option<PathBuf>,
+}
+
+/// Listener of the standard HTTP proxy requests, so that the usual clients can use the server as
+/// `HTTP_PROXY` without speaking the custom protocol
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    /// The listener is disabled when missing
+    pub bind: Option<SocketAddr>,
+    /// The requests with bigger bodies are refused, as the body is read into memory
+    pub max_body_size: usize,
+    /// The client has to send the request headers during this period, and the tunnels are closed
+    /// once nothing is sent in either direction during it
+    pub idle_timeout_secs: u64,
+    /// The next connections wait to be accepted until some of these are closed
+    pub max_connections: usize,
+}
+
+/// SOCKS5 listener for the clients which don't support the HTTP proxies
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SocksConfig {
+    /// The listener is disabled when missing
+    pub bind: Option<SocketAddr>,
+    /// The client has to send its request during this period after connecting
+    pub handshake_timeout_secs: u64,
+    /// The tunnels and the UDP relays are closed once nothing is relayed during this period
+    pub idle_timeout_secs: u64,
+    /// The next connections wait to be accepted until some of these are closed
+    pub max_connections: usize,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UdpConfig {
+    pub bind: SocketAddr,
+    /// Using max message size as 10000 by default, as the target server won't even be able to
+    /// handle even longer URLs.
+    /// Source: https://stackoverflow.com/questions/417142/what-is-the-maximum-length-of-a-url-in-different-browsers
+    pub max_message_size: usize,
+    /// Size of the whole datagram of a response batch, including the headers
+    pub batch_size: usize,
+    /// Capacity of the requests and responses queues between the socket and the tasks handler
+    pub channel_capacity: usize,
+    /// How long the sent batches are kept for the repeat requests
+    pub cache_ttl_secs: u64,
+    /// How long the greeting of a silent client is remembered
+    pub session_ttl_secs: u64,
+    /// Refusing the clients which don't agree on the encryption during the greeting
+    pub require_encryption: bool,
+    /// Until the client proves it owns its address, at most this many times the bytes received
+    /// from the address are sent to it, so that a spoofed request can't flood somebody else.
+    /// Zero disables the limit, which is the default, as only the clients agreeing on the address
+    /// validation or the encryption can prove it
+    pub amplification_factor: usize,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProxyConfig {
+    /// Replacing the body of non-successful upstream responses with a generated HTML page, the
+    /// status and the headers are passed to the client either way
+    pub html_error_pages: bool,
+    /// Removed from the client headers before forwarding and from the upstream response headers.
+    /// The hop-by-hop headers by default, as they only make sense for a single connection
+    pub strip_headers: Vec<String>,
+    /// Always added to the forwarded requests, replacing the client supplied values
+    pub inject_headers: BTreeMap<String, String>,
+    /// The target servers resolving to these networks are refused, so that the proxy can't be
+    /// used for reaching the internal network. The private, loopback, link-local and other
+    /// special purpose ranges by default
+    pub denied_networks: Vec<IpNet>,
+    /// Exceptions from the denied networks
+    pub allowed_networks: Vec<IpNet>,
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        ProxyConfig {
+            html_error_pages: false,
+            strip_headers: [
+                "connection", "keep-alive", "proxy-authenticate", "proxy-authorization", "proxy-connection",
+                "te", "trailer", "transfer-encoding", "upgrade",
+            ].iter().map(|h| h.to_string()).collect(),
+            inject_headers: BTreeMap::from([("via".to_owned(), "1.1 rust_proxy_server".to_owned())]),
+            denied_networks: [
+                "0.0.0.0/8", "10.0.0.0/8", "100.64.0.0/10", "127.0.0.0/8", "169.254.0.0/16", "172.16.0.0/12",
+                "192.0.0.0/24", "192.168.0.0/16", "198.18.0.0/15", "224.0.0.0/4", "240.0.0.0/4",
+                "::/128", "::1/128", "64:ff9b::/96", "fc00::/7", "fe80::/10", "ff00::/8",
+            ].iter().map(|n| n.parse().unwrap()).collect(),
+            allowed_networks: Vec::new(),
+        }
+    }
+}
+
+/// Rules for the requested URLs, applied the same way for the TCP and UDP clients
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PolicyConfig {
+    pub allowed_schemes: Vec<String>,
+    /// Host patterns, like `example.com` or `*.example.com`. All hosts are allowed when empty
+    pub allowed_hosts: Vec<String>,
+    /// Host patterns which are refused even if they are allowed
+    pub denied_hosts: Vec<String>,
+    /// All ports are allowed when empty
+    pub allowed_ports: Vec<u16>,
+    pub max_url_length: usize,
+}
+
+/// Responses shared between all the clients, honoring the caching headers of the target servers
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    /// Overall size of the cached responses in bytes, zero disables the cache
+    pub max_size: usize,
+    /// Bigger responses are passed through without being cached
+    pub max_entry_size: usize,
+    /// The responses are also kept in this directory across the restarts when given
+    pub disk_path: Option<PathBuf>,
+    /// Overall size of the bodies kept on disk in bytes
+    pub disk_max_size: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            max_size: 64 * 1024 * 1024,
+            max_entry_size: 8 * 1024 * 1024,
+            disk_path: None,
+            disk_max_size: 1024 * 1024 * 1024,
+        }
+    }
+}
+
+/// Clients allowed to connect, the authentication is disabled when there are none
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Token per client name
+    pub tokens: BTreeMap<String, String>,
+}
+
+impl Default for PolicyConfig {
+    fn default() -> Self {
+        PolicyConfig {
+            allowed_schemes: vec!["http".to_owned(), "https".to_owned()],
+            allowed_hosts: Vec::new(),
+            denied_hosts: Vec::new(),
+            allowed_ports: Vec::new(),
+            max_url_length: 8192,
+        }
+    }
+}
+
+impl Default for TcpConfig {
+    fn default() -> Self {
+        TcpConfig {
+            bind: "0.0.0.0:4000".parse().unwrap(),
+            max_message_size: 10000,
+            idle_timeout_secs: 60,
+            max_response_size: 16 * 1024 * 1024,
+            tls: None,
+        }
+    }
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            bind: None,
+            max_body_size: 10 * 1024 * 1024,
+            idle_timeout_secs: 60,
+            max_connections: 1024,
+        }
+    }
+}
+
+impl Default for SocksConfig {
+    fn default() -> Self {
+        SocksConfig {
+            bind: None,
+            handshake_timeout_secs: 10,
+            idle_timeout_secs: 60,
+            max_connections: 1024,
+        }
+    }
+}
+
+impl Default for UdpConfig {
+    fn default() -> Self {
+        UdpConfig {
+            bind: "0.0.0.0:4000".parse().unwrap(),
+            max_message_size: 10000,
+            batch_size: 1000,
+            channel_capacity: 100,
+            cache_ttl_secs: 60 * 5,
+            session_ttl_secs: 60 * 10,
+            require_encryption: false,
+            amplification_factor: 0,
+        }
+    }
+}
+
+impl TcpConfig {
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout_secs)
+    }
+}
+
+impl HttpConfig {
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout_secs)
+    }
+}
+
+impl SocksConfig {
+    pub fn handshake_timeout(&self) -> Duration {
+        Duration::from_secs(self.handshake_timeout_secs)
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout_secs)
+    }
+}
+
+impl UdpConfig {
+    pub fn cache_ttl(&self) -> Duration {
+        Duration::from_secs(self.cache_ttl_secs)
+    }
+
+    pub fn session_ttl(&self) -> Duration {
+        Duration::from_secs(self.session_ttl_secs)
+    }
+}
+
+impl Config {
+    /// Reading the config file given in the command line (if any) and applying the command line
+    /// flags on top of it
+    pub fn load() -> Result<Self, String> {
+        let cli = Cli::parse();
+        let mut config = match &cli.config {
+            Some(path) => Self::from_file(path)?,
+            None => Config::default(),
+        };
+        config.apply_cli(cli)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn from_file(path: &PathBuf) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed reading the config file {}: {}", path.display(), e))?;
+        toml::from_str(&content)
+            .map_err(|e| format!("Failed parsing the config file {}: {}", path.display(), e))
+    }
+
+    fn apply_cli(&mut self, cli: Cli) -> Result<(), String> {
+        if let Some(v) = cli.tcp_bind { self.tcp.bind = v; }
+        if let Some(v) = cli.tcp_max_message_size { self.tcp.max_message_size = v; }
+        if let Some(v) = cli.tcp_idle_timeout_secs { self.tcp.idle_timeout_secs = v; }
+        if let Some(v) = cli.tcp_max_response_size { self.tcp.max_response_size = v; }
+        self.apply_tls_cli(cli.tls_cert, cli.tls_key, cli.tls_client_ca)?;
+        if let Some(v) = cli.http_bind { self.http.bind = Some(v); }
+        if let Some(v) = cli.http_max_body_size { self.http.max_body_size = v; }
+        if let Some(v) = cli.http_idle_timeout_secs { self.http.idle_timeout_secs = v; }
+        if let Some(v) = cli.http_max_connections { self.http.max_connections = v; }
+        if let Some(v) = cli.socks_bind { self.socks.bind = Some(v); }
+        if let Some(v) = cli.socks_handshake_timeout_secs { self.socks.handshake_timeout_secs = v; }
+        if let Some(v) = cli.socks_idle_timeout_secs { self.socks.idle_timeout_secs = v; }
+        if let Some(v) = cli.socks_max_connections { self.socks.max_connections = v; }
+        if let Some(v) = cli.udp_bind { self.udp.bind = v; }
+        if let Some(v) = cli.udp_max_message_size { self.udp.max_message_size = v; }
+        if let Some(v) = cli.udp_batch_size { self.udp.batch_size = v; }
+        if let Some(v) = cli.udp_channel_capacity { self.udp.channel_capacity = v; }
+        if let Some(v) = cli.udp_cache_ttl_secs { self.udp.cache_ttl_secs = v; }
+        if let Some(v) = cli.udp_session_ttl_secs { self.udp.session_ttl_secs = v; }
+        if cli.udp_require_encryption { self.udp.require_encryption = true; }
+        if let Some(v) = cli.udp_amplification_factor { self.udp.amplification_factor = v; }
+        if cli.html_error_pages { self.proxy.html_error_pages = true; }
+        if !cli.strip_headers.is_empty() { self.proxy.strip_headers = cli.strip_headers; }
+        if !cli.inject_headers.is_empty() { self.proxy.inject_headers = cli.inject_headers.into_iter().collect(); }
+        if !cli.denied_networks.is_empty() { self.proxy.denied_networks = cli.denied_networks; }
+        if !cli.allowed_networks.is_empty() { self.proxy.allowed_networks = cli.allowed_networks; }
+        if !cli.allowed_schemes.is_empty() { self.policy.allowed_schemes = cli.allowed_schemes; }
+        if !cli.allowed_hosts.is_empty() { self.policy.allowed_hosts = cli.allowed_hosts; }
+        if !cli.denied_hosts.is_empty() { self.policy.denied_hosts = cli.denied_hosts; }
+        if !cli.allowed_ports.is_empty() { self.policy.allowed_ports = cli.allowed_ports; }
+        if let Some(v) = cli.cache_max_size { self.cache.max_size = v; }
+        if let Some(v) = cli.cache_max_entry_size { self.cache.max_entry_size = v; }
+        if let Some(v) = cli.cache_dir { self.cache.disk_path = Some(v); }
+        if let Some(v) = cli.cache_disk_max_size { self.cache.disk_max_size = v; }
+        if let Some(v) = cli.max_url_length { self.policy.max_url_length = v; }
+        if !cli.auth_tokens.is_empty() { self.auth.tokens = cli.auth_tokens.into_iter().collect(); }
+        Ok(())
+    }
+
+    /// Every flag replaces only its own field of the TLS config from the file
+    fn apply_tls_cli(&mut self, cert_path: Option<PathBuf>, key_path: Option<PathBuf>, client_ca_path: Option<PathBuf>) -> Result<(), String> {
+        if cert_path.is_none() && key_path.is_none() && client_ca_path.is_none() {
+            return Ok(());
+        }
+        let tls = match (self.tcp.tls.take(), cert_path, key_path) {
+            (Some(tls), cert_path, key_path) => TlsConfig {
+                cert_path: cert_path.unwrap_or(tls.cert_path),
+                key_path: key_path.unwrap_or(tls.key_path),
+                client_ca_path: client_ca_path.or(tls.client_ca_path),
+            },
+            (None, Some(cert_path), Some(key_path)) => TlsConfig { cert_path, key_path, client_ca_path },
+            (None, _, _) => return Err("TLS needs both the certificate and the key, either in the config file or in the flags".to_owned()),
+        };
+        self.tcp.tls = Some(tls);
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.udp.batch_size <= HEADERS_BYTES_COUNT {
+            return Err(format!("UDP batch size should be bigger than the headers size {}", HEADERS_BYTES_COUNT));
+        }
+        // The batches of the encrypted sessions are sent with the cipher overhead added
+        if self.udp.batch_size + ENCRYPTION_OVERHEAD > MAX_UDP_PAYLOAD_SIZE {
+            return Err(format!(
+                "UDP batch size should fit into a single encrypted datagram, max is {}",
+                MAX_UDP_PAYLOAD_SIZE - ENCRYPTION_OVERHEAD
+            ));
+        }
+        if self.udp.max_message_size == 0 {
+            return Err("UDP max message size should be positive".to_owned());
+        }
+        if self.tcp.max_message_size == 0 {
+            return Err("TCP max message size should be positive".to_owned());
+        }
+        if self.tcp.max_response_size == 0 {
+            return Err("TCP max response size should be positive".to_owned());
+        }
+        if self.udp.cache_ttl_secs == 0 {
+            return Err("UDP batches cache TTL should be positive".to_owned());
+        }
+        if self.tcp.idle_timeout_secs == 0 {
+            return Err("TCP idle timeout should be positive".to_owned());
+        }
+        if self.udp.channel_capacity == 0 {
+            return Err("UDP channel capacity should be positive".to_owned());
+        }
+        if self.http.idle_timeout_secs == 0 {
+            return Err("HTTP proxy idle timeout should be positive".to_owned());
+        }
+        if self.http.max_connections == 0 {
+            return Err("HTTP proxy max connections should be positive".to_owned());
+        }
+        if self.socks.handshake_timeout_secs == 0 {
+            return Err("SOCKS handshake timeout should be positive".to_owned());
+        }
+        if self.socks.idle_timeout_secs == 0 {
+            return Err("SOCKS idle timeout should be positive".to_owned());
+        }
+        if self.socks.max_connections == 0 {
+            return Err("SOCKS max connections should be positive".to_owned());
+        }
+        if self.udp.session_ttl_secs == 0 {
+            return Err("UDP session TTL should be positive".to_owned());
+        }
+        for (name, token) in self.auth.tokens.iter() {
+            if name.is_empty() || name.contains(':') || token.is_empty() {
+                return Err(format!("Invalid auth client {}, the name can't contain `:` and the token can't be empty", name));
+            }
+        }
+        for name in self.proxy.strip_headers.iter() {
+            HeaderName::from_bytes(name.as_bytes())
+                .map_err(|e| format!("Invalid header name {} to strip: {}", name, e))?;
+        }
+        for (name, value) in self.proxy.inject_headers.iter() {
+            HeaderName::from_bytes(name.as_bytes())
+                .map_err(|e| format!("Invalid header name {} to inject: {}", name, e))?;
+            HeaderValue::from_str(value)
+                .map_err(|e| format!("Invalid value of the header {} to inject: {}", name, e))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,91 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use hyper::client::connect::dns::Name;
+use ipnet::IpNet;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use reqwest::Url;
+
+/// Returned when the target server resolves only to the denied addresses
+#[derive(Debug)]
+pub struct DestinationDenied {
+    pub host: String,
+}
+
+impl Display for DestinationDenied {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Destination {} is not allowed by the proxy", self.host)
+    }
+}
+
+impl Error for DestinationDenied {}
+
+/// Protecting the internal network from being reached through the proxy. An address is
+/// rejected if it's in one of the denied networks, unless it's also in one of the allowed ones
+pub struct DestinationFilter {
+    denied_networks: Vec<IpNet>,
+    allowed_networks: Vec<IpNet>,
+}
+
+impl DestinationFilter {
+    pub fn new(denied_networks: Vec<IpNet>, allowed_networks: Vec<IpNet>) -> Self {
+        DestinationFilter { denied_networks, allowed_networks }
+    }
+
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        // IPv4-mapped IPv6 addresses are reaching the IPv4 hosts, so checking them as such
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            IpAddr::V4(_) => ip,
+        };
+        self.allowed_networks.iter().any(|n| n.contains(&ip))
+            || !self.denied_networks.iter().any(|n| n.contains(&ip))
+    }
+
+    /// Checking the URLs with IP address hosts, as those are not going through the resolver
+    pub fn check_url(&self, url: &Url) -> Result<(), DestinationDenied> {
+        let ip = match url
+            .host_str()
+            .and_then(|host| host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>().ok())
+        {
+            Some(ip) => ip,
+            None => return Ok(()),
+        };
+        if self.is_allowed(ip) {
+            Ok(())
+        } else {
+            Err(DestinationDenied { host: ip.to_string() })
+        }
+    }
+}
+
+/// Filtering the resolved addresses before the HTTP client connects to them, so that the
+/// checked addresses are exactly the ones being used and DNS rebinding can't sneak in another one
+pub struct FilteringResolver {
+    filter: Arc<DestinationFilter>,
+}
+
+impl FilteringResolver {
+    pub fn new(filter: Arc<DestinationFilter>) -> Self {
+        FilteringResolver { filter }
+    }
+}
+
+impl Resolve for FilteringResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let filter = self.filter.clone();
+        Box::pin(async move {
+            let host = name.as_str().to_owned();
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0))
+                .await?
+                .filter(|addr| filter.is_allowed(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(Box::new(DestinationDenied { host }) as Box<dyn Error + Send + Sync>);
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
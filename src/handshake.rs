@@ -0,0 +1,161 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+
+use crate::authenticator::Authenticator;
+use crate::compression::Compression;
+
+const CONNECT_MESSAGE: &str = "Connect";
+const ACCEPT_RESPONSE: &str = "Accept";
+const CHALLENGE_RESPONSE: &str = "Challenge";
+const REJECT_RESPONSE: &str = "Reject";
+/// The latest protocol version the server speaks
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// What was agreed with the client during the greeting
+pub struct SessionParameters {
+    pub version: u32,
+    pub capabilities: Vec<String>,
+    /// Values the client gave for the capabilities, like the public key for the encryption
+    pub client_arguments: BTreeMap<String, String>,
+    /// Values the server gives back for the capabilities in the accept response
+    pub server_arguments: BTreeMap<String, String>,
+    /// Name of the authenticated client, if the authentication is enabled
+    pub client: Option<String>,
+}
+
+impl SessionParameters {
+    fn new(version: u32, capabilities: Vec<String>, client_arguments: BTreeMap<String, String>, client: Option<String>) -> Self {
+        SessionParameters { version, capabilities, client_arguments, server_arguments: BTreeMap::new(), client }
+    }
+
+    pub fn has_capability(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+
+    pub fn accept_response(&self) -> String {
+        let capabilities: Vec<String> = self
+            .capabilities
+            .iter()
+            .map(|c| match self.server_arguments.get(c) {
+                Some(argument) => format!("{}={}", c, argument),
+                None => c.clone(),
+            })
+            .collect();
+        format!("{}:{}:{}", ACCEPT_RESPONSE, self.version, capabilities.join(","))
+    }
+}
+
+pub enum HandshakeOutcome {
+    /// The session is established, the response is `Accept:...`
+    Accepted(Box<SessionParameters>, String),
+    /// The client has to sign the challenge and connect again, the response is `Challenge:...`
+    Challenged(String),
+    /// The response is `Reject:<reason>`
+    Rejected(String),
+}
+
+pub struct Handshake {}
+
+/// The greeting is `Connect:<version>:<capability>,<capability>,...[:<credentials>]` and the server
+/// answers with `Accept:<version>:<capabilities>`, where the version is the highest one both sides
+/// speak and the capabilities are the ones both sides support. A capability can carry an argument
+/// as `<capability>=<argument>`, in both directions. Only the capabilities which change how the
+/// session behaves are negotiated, the rest of the version 1 format is always there. The bare
+/// `Connect` is the same as `Connect:1:` and is answered with the bare `Accept`.
+///
+/// When the authentication is enabled, the credentials are either `token=<token>`, or
+/// `hmac=<client name>`. The latter is answered with `Challenge:<challenge>` and the client should
+/// connect again with `hmac=<client name>:<challenge>:<hex HMAC-SHA256 of the challenge keyed by the token>`
+impl Handshake {
+    pub fn is_connect_message(message: &str) -> bool {
+        message == CONNECT_MESSAGE || message.starts_with(&format!("{}:", CONNECT_MESSAGE))
+    }
+
+    /// Fails only for the malformed messages, the authentication failures are rejections
+    pub fn negotiate(
+        message: &str,
+        supported_capabilities: &[&str],
+        authenticator: &Authenticator,
+        peer: SocketAddr,
+    ) -> Result<HandshakeOutcome, String> {
+        if message == CONNECT_MESSAGE {
+            if authenticator.is_enabled() {
+                return Ok(Self::reject(peer, "Authentication required"));
+            }
+            let parameters = SessionParameters::new(1, Vec::new(), BTreeMap::new(), None);
+            return Ok(HandshakeOutcome::Accepted(Box::new(parameters), ACCEPT_RESPONSE.to_owned()));
+        }
+        let mut parts = message
+            .strip_prefix(&format!("{}:", CONNECT_MESSAGE))
+            .ok_or_else(|| "Expected connect message".to_owned())?
+            .splitn(3, ':');
+        let version: u32 = parts
+            .next()
+            .and_then(|v| v.parse().ok())
+            .filter(|v| *v > 0)
+            .ok_or_else(|| "Invalid protocol version in the connect message".to_owned())?;
+        let mut capabilities = Vec::new();
+        let mut client_arguments = BTreeMap::new();
+        for capability in parts.next().unwrap_or("").split(',') {
+            let (name, argument) = match capability.trim().split_once('=') {
+                Some((name, argument)) => (name, Some(argument)),
+                None => (capability.trim(), None),
+            };
+            if supported_capabilities.contains(&name) {
+                capabilities.push(name.to_owned());
+                if let Some(argument) = argument {
+                    client_arguments.insert(name.to_owned(), argument.to_owned());
+                }
+            }
+        }
+        let client = if authenticator.is_enabled() {
+            match Self::authenticate(parts.next(), authenticator, peer) {
+                Ok(client) => Some(client),
+                Err(outcome) => return Ok(outcome),
+            }
+        } else {
+            None
+        };
+        let mut parameters = SessionParameters::new(version.min(PROTOCOL_VERSION), capabilities, client_arguments, client);
+        Compression::negotiate(&mut parameters);
+        let response = parameters.accept_response();
+        Ok(HandshakeOutcome::Accepted(Box::new(parameters), response))
+    }
+
+    /// Returns the client name when authenticated, otherwise the challenge or the rejection
+    fn authenticate(credentials: Option<&str>, authenticator: &Authenticator, peer: SocketAddr) -> Result<String, HandshakeOutcome> {
+        let credentials = credentials.ok_or_else(|| Self::reject(peer, "Authentication required"))?;
+        if let Some(token) = credentials.strip_prefix("token=") {
+            return authenticator.check_token(token).map_err(|reason| Self::reject(peer, &reason));
+        }
+        if let Some(hmac_credentials) = credentials.strip_prefix("hmac=") {
+            let mut hmac_parts = hmac_credentials.splitn(3, ':');
+            let name = hmac_parts.next().unwrap_or("");
+            return match (hmac_parts.next(), hmac_parts.next()) {
+                (Some(challenge), Some(response)) => authenticator
+                    .verify_challenge_response(name, peer, challenge, response)
+                    .map(|_| name.to_owned())
+                    .map_err(|reason| Self::reject(peer, &format!("{} for client {}", reason, name))),
+                _ => Err(Self::challenge(name, authenticator, peer)),
+            };
+        }
+        Err(Self::reject(peer, "Unsupported credentials, use token= or hmac="))
+    }
+
+    fn challenge(name: &str, authenticator: &Authenticator, peer: SocketAddr) -> HandshakeOutcome {
+        match authenticator.create_challenge(name, peer) {
+            Ok(challenge) => HandshakeOutcome::Challenged(format!("{}:{}", CHALLENGE_RESPONSE, challenge)),
+            Err(reason) => Self::reject(peer, &reason),
+        }
+    }
+
+    fn reject(peer: SocketAddr, reason: &str) -> HandshakeOutcome {
+        HandshakeOutcome::Rejected(Self::rejection(peer, reason))
+    }
+
+    /// Logged, as the rejections are worth noticing
+    pub fn rejection(peer: SocketAddr, reason: &str) -> String {
+        println!("Rejected the connection from {}: {}", peer, reason);
+        format!("{}:{}", REJECT_RESPONSE, reason)
+    }
+}
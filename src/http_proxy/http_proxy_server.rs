@@ -0,0 +1,278 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use hyper::body::HttpBody;
+use hyper::header::{HeaderName, HeaderValue, CONNECTION, HOST, PROXY_AUTHENTICATE, PROXY_AUTHORIZATION};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, HeaderMap, Method, Request, Response, StatusCode};
+use tokio::net::TcpListener;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::authenticator::Authenticator;
+use crate::config::HttpConfig;
+use crate::proxy_logic::ProxyLogic;
+use crate::proxy_request::ProxyRequest;
+use crate::proxy_response::ProxyResponse;
+use crate::proxy_response_stream::ProxyResponseStream;
+use crate::tunnel_relay::TunnelRelay;
+
+const AUTHENTICATE_CHALLENGE: &str = "Basic realm=\"rust_proxy_server\"";
+/// Used for the CONNECT targets without a port
+const DEFAULT_TUNNEL_PORT: u16 = 443;
+/// Describing the connection to the proxy rather than the request, so they are never passed on,
+/// along with the headers named in `Connection`
+const HOP_BY_HOP_HEADERS: &[&str] = &["connection", "proxy-connection", "keep-alive", "te", "trailer", "transfer-encoding", "upgrade"];
+
+/// Standard HTTP/1.1 proxy: the requests with absolute URIs are fetched the same way as the
+/// custom protocol ones, and the CONNECT requests open tunnels. Both are going through the
+/// same URL policy and destination filter.
+/// When the authentication is enabled, the clients send `Proxy-Authorization: Basic` with
+/// their name and token
+pub struct HttpProxyServer {
+    proxy_logic: Arc<ProxyLogic>,
+    authenticator: Arc<Authenticator>,
+    max_body_size: usize,
+    /// The client has to send the request headers during this period, and the tunnels are closed
+    /// once idle for it
+    idle_timeout: Duration,
+    max_connections: usize,
+}
+
+impl HttpProxyServer {
+    /// Taking the limits from the config
+    pub fn new(proxy_logic: Arc<ProxyLogic>, authenticator: Arc<Authenticator>, config: &HttpConfig) -> Self {
+        HttpProxyServer {
+            proxy_logic,
+            authenticator,
+            max_body_size: config.max_body_size,
+            idle_timeout: config.idle_timeout(),
+            max_connections: config.max_connections,
+        }
+    }
+
+    pub async fn start(self, bind: SocketAddr) -> Result<(), String> {
+        println!("Starting the HTTP proxy server...");
+        let listener = TcpListener::bind(bind)
+            .await
+            .map_err(|e| format!("Failed binding the HTTP proxy listener to {}: {}", bind, e))?;
+        let mut http = Http::new();
+        http.http1_only(true).http1_header_read_timeout(self.idle_timeout);
+        let connections = Arc::new(Semaphore::new(self.max_connections));
+        let server = Arc::new(self);
+        loop {
+            // Shared with the tunnel of the connection, which outlives the served connection
+            let permit = Arc::new(connections
+                .clone()
+                .acquire_owned()
+                .await
+                .expect("The connections semaphore is never closed"));
+            let (stream, peer) = listener
+                .accept()
+                .await
+                .map_err(|e| format!("HTTP proxy listener failed accepting: {}", e))?;
+            let server = server.clone();
+            let connection_permit = permit.clone();
+            let service = service_fn(move |request| {
+                let server = server.clone();
+                let permit = connection_permit.clone();
+                async move { Ok::<_, Infallible>(server.handle(request, peer, permit).await) }
+            });
+            let connection = http.serve_connection(stream, service).with_upgrades();
+            tokio::spawn(async move {
+                if let Err(e) = connection.await {
+                    println!("HTTP proxy client {} failed: {}", peer, e);
+                }
+                drop(permit);
+            });
+        }
+    }
+
+    async fn handle(&self, request: Request<Body>, peer: SocketAddr, permit: Arc<OwnedSemaphorePermit>) -> Response<Body> {
+        if let Some(response) = self.authentication_refusal(&request, peer) {
+            return Self::respond(ProxyResponseStream::buffered(response));
+        }
+        let stream = if request.method() == Method::CONNECT {
+            match self.open_tunnel(request, peer, permit).await {
+                Ok(response) => return response,
+                Err(response) => ProxyResponseStream::buffered(response),
+            }
+        } else {
+            match self.fetch(request).await {
+                Ok(stream) => stream,
+                Err(response) => ProxyResponseStream::buffered(response),
+            }
+        };
+        Self::respond(stream)
+    }
+
+    /// Returns the response to send instead of serving the request when the client isn't authenticated
+    fn authentication_refusal(&self, request: &Request<Body>, peer: SocketAddr) -> Option<ProxyResponse> {
+        if !self.authenticator.is_enabled() {
+            return None;
+        }
+        let result = request
+            .headers()
+            .get(PROXY_AUTHORIZATION)
+            .ok_or_else(|| "Missing proxy credentials".to_owned())
+            .and_then(|value| self.check_credentials(value));
+        match result {
+            Ok(name) => {
+                println!("HTTP proxy client {} authenticated as {}", peer, name);
+                None
+            }
+            Err(e) => {
+                println!("HTTP proxy client {} not authenticated: {}", peer, e);
+                let mut response = ProxyResponse::proxy_error(StatusCode::PROXY_AUTHENTICATION_REQUIRED, "auth-required", e);
+                response.headers.insert(PROXY_AUTHENTICATE, HeaderValue::from_static(AUTHENTICATE_CHALLENGE));
+                Some(response)
+            }
+        }
+    }
+
+    /// The credentials are `Basic base64(name:token)`, returns the client name
+    fn check_credentials(&self, value: &HeaderValue) -> Result<String, String> {
+        let invalid = || "Invalid proxy credentials".to_owned();
+        let encoded = value
+            .to_str()
+            .ok()
+            .and_then(|v| v.strip_prefix("Basic "))
+            .ok_or_else(invalid)?;
+        let decoded = BASE64.decode(encoded.trim()).map_err(|_| invalid())?;
+        let decoded = String::from_utf8(decoded).map_err(|_| invalid())?;
+        let (name, token) = decoded.split_once(':').ok_or_else(invalid)?;
+        match self.authenticator.check_token(token)? {
+            owner if owner == name => Ok(owner),
+            _ => Err(invalid()),
+        }
+    }
+
+    async fn fetch(&self, request: Request<Body>) -> Result<ProxyResponseStream, ProxyResponse> {
+        let (parts, body) = request.into_parts();
+        if parts.uri.scheme().is_none() || parts.uri.authority().is_none() {
+            return Err(ProxyResponse::proxy_error(
+                StatusCode::BAD_REQUEST,
+                "url-invalid",
+                format!("Expected an absolute URI, got {}", parts.uri),
+            ));
+        }
+        let body = self.read_body(body).await?;
+        let mut headers = parts.headers;
+        // The target is in the URL, and the credentials are for the proxy only
+        headers.remove(HOST);
+        headers.remove(PROXY_AUTHORIZATION);
+        Self::remove_hop_by_hop_headers(&mut headers);
+        let proxy_request = ProxyRequest {
+            method: parts.method,
+            url: parts.uri.to_string(),
+            headers,
+            body: if body.is_empty() { None } else { Some(body) },
+        };
+        self.proxy_logic.fetch(&proxy_request).await.map_err(|e| {
+            println!("Failed fetching {}: {}", proxy_request.url, e);
+            ProxyResponse::proxy_error(StatusCode::BAD_GATEWAY, "upstream-failed", e)
+        })
+    }
+
+    async fn read_body(&self, mut body: Body) -> Result<Vec<u8>, ProxyResponse> {
+        let mut content = Vec::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.map_err(|e| ProxyResponse::proxy_error(
+                StatusCode::BAD_REQUEST,
+                "body-invalid",
+                format!("Failed reading the request body: {}", e),
+            ))?;
+            if content.len() + chunk.len() > self.max_body_size {
+                return Err(ProxyResponse::proxy_error(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    "body-too-large",
+                    format!("Request body is bigger than {} bytes", self.max_body_size),
+                ));
+            }
+            content.extend(chunk);
+        }
+        Ok(content)
+    }
+
+    /// The tunnel is relayed once the client gets the 200 response and the connection is handed over.
+    /// The connection permit is held until the tunnel is closed
+    async fn open_tunnel(
+        &self,
+        mut request: Request<Body>,
+        peer: SocketAddr,
+        permit: Arc<OwnedSemaphorePermit>,
+    ) -> Result<Response<Body>, ProxyResponse> {
+        let authority = request.uri().authority().cloned().ok_or_else(|| ProxyResponse::proxy_error(
+            StatusCode::BAD_REQUEST,
+            "url-invalid",
+            format!("Expected host:port, got {}", request.uri()),
+        ))?;
+        let port = authority.port_u16().unwrap_or(DEFAULT_TUNNEL_PORT);
+        let target = self.proxy_logic.open_tunnel(authority.host(), port).await?;
+        let upgrade = hyper::upgrade::on(&mut request);
+        let idle_timeout = self.idle_timeout;
+        tokio::spawn(async move {
+            let result = match upgrade.await {
+                Ok(upgraded) => TunnelRelay::relay(tokio::io::split(upgraded), target.into_split(), idle_timeout).await,
+                Err(e) => Err(e.to_string()),
+            };
+            match result {
+                Ok((sent, received)) => println!(
+                    "Tunnel of {} to {} closed after sending {} and receiving {} bytes",
+                    peer, authority, sent, received
+                ),
+                Err(e) => println!("Tunnel of {} to {} failed: {}", peer, authority, e),
+            }
+            drop(permit);
+        });
+        Ok(Response::new(Body::empty()))
+    }
+
+    /// The body is passed on while being read from the target server
+    fn respond(mut stream: ProxyResponseStream) -> Response<Body> {
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = stream.status();
+        *response.headers_mut() = stream.headers().clone();
+        Self::remove_hop_by_hop_headers(response.headers_mut());
+        let (mut sender, body) = Body::channel();
+        *response.body_mut() = body;
+        tokio::spawn(async move {
+            loop {
+                match stream.next_body_chunk().await {
+                    Ok(Some(chunk)) => {
+                        if sender.send_data(chunk.into()).await.is_err() {
+                            break;
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        println!("{}", e);
+                        sender.abort();
+                        break;
+                    }
+                }
+            }
+        });
+        response
+    }
+
+    fn remove_hop_by_hop_headers(headers: &mut HeaderMap) {
+        let named: Vec<HeaderName> = headers
+            .get_all(CONNECTION)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+            .collect();
+        for name in named {
+            headers.remove(name);
+        }
+        for name in HOP_BY_HOP_HEADERS {
+            headers.remove(*name);
+        }
+    }
+}
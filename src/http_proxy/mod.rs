@@ -0,0 +1 @@
+pub mod http_proxy_server;
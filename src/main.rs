@@ -1,52 +1,104 @@
+use std::sync::Arc;
+
 use tokio::net::UdpSocket;
 use tokio::sync::mpsc;
 
 use tcp::custom_tcp_listener::CustomTcpListener;
 use tcp::tcp_server::TcpServer;
+use tcp::tls;
 
+use crate::authenticator::Authenticator;
+use crate::config::Config;
+use crate::http_proxy::http_proxy_server::HttpProxyServer;
+use crate::proxy_logic::ProxyLogic;
+use crate::socks::socks_server::SocksServer;
+use crate::udp::address_validator::AddressValidator;
 use crate::udp::custom_udp_socket::CustomUdpSocket;
 use crate::udp::udp_server::UdpServer;
 use crate::udp::udp_server_tasks_handler::UdpServerTasksHandler;
+use crate::udp::udp_sessions::UdpSessions;
 
+mod authenticator;
+mod cache;
+mod compression;
+mod config;
+mod destination_filter;
+mod handshake;
+mod http_proxy;
+mod socks;
 mod tcp;
 mod udp;
 mod toolkit;
+mod tunnel_relay;
 
 mod proxy_logic;
+mod proxy_request;
+mod proxy_response;
+mod proxy_response_stream;
+mod url_policy;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // TODO Allow defining the port from a CLI
+    let config = Config::load()?;
+    let proxy_logic = Arc::new(ProxyLogic::new(config.proxy, config.policy, &config.cache)?);
+    let authenticator = Arc::new(Authenticator::new(config.auth.tokens));
     let mut promises = vec![];
 
     // Setting up UDP server
-    let socket = UdpSocket::bind("0.0.0.0:4000").await?;
-    let (request_sender, request_receiver) = mpsc::channel(100);
-    let (response_sender, response_receiver) = mpsc::channel(100);
+    let socket = UdpSocket::bind(config.udp.bind).await?;
+    let (request_sender, request_receiver) = mpsc::channel(config.udp.channel_capacity);
+    let (response_sender, response_receiver) = mpsc::channel(config.udp.channel_capacity);
+    let udp_max_message_size = config.udp.max_message_size;
+    let udp_sessions = Arc::new(UdpSessions::new(config.udp.session_ttl(), config.udp.require_encryption));
+    let socket_udp_sessions = udp_sessions.clone();
+    let address_validator = Arc::new(AddressValidator::new(config.udp.amplification_factor));
+    let socket_address_validator = address_validator.clone();
     promises.push(tokio::spawn(async move {
         UdpServer::new(
-            CustomUdpSocket::new(socket),
+            CustomUdpSocket::new(socket, udp_max_message_size, socket_udp_sessions, socket_address_validator),
             request_sender,
             response_receiver,
         ).start().await;
     }));
 
+    let udp_proxy_logic = proxy_logic.clone();
+    let udp_authenticator = authenticator.clone();
     promises.push(tokio::spawn(async move {
         UdpServerTasksHandler::new(
             request_receiver,
             response_sender,
+            udp_proxy_logic,
+            &config.udp,
+            udp_authenticator,
+            udp_sessions,
+            address_validator,
         ).start().await;
     }));
 
     // Setting up TCP server
-    let tcp_listener = CustomTcpListener::new("0.0.0.0:4000".parse().unwrap()).await?;
+    let tls_acceptor = config.tcp.tls.as_ref().map(tls::load_acceptor).transpose()?;
+    let tcp_listener = CustomTcpListener::new(config.tcp.bind, config.tcp.max_message_size, tls_acceptor).await?;
+    let tcp_server = TcpServer::new(proxy_logic.clone(), authenticator.clone(), &config.tcp);
     promises.push(tokio::spawn(async move {
-        TcpServer {}.start(tcp_listener).await.expect("TCP server failed running");
+        tcp_server.start(tcp_listener).await.expect("TCP server failed running");
     }));
 
+    // Setting up the HTTP proxy server, if enabled
+    if let Some(bind) = config.http.bind {
+        let http_server = HttpProxyServer::new(proxy_logic.clone(), authenticator.clone(), &config.http);
+        promises.push(tokio::spawn(async move {
+            http_server.start(bind).await.expect("HTTP proxy server failed running");
+        }));
+    }
+
+    // Setting up the SOCKS5 server, if enabled
+    if let Some(bind) = config.socks.bind {
+        let socks_server = SocksServer::new(proxy_logic, authenticator, &config.socks);
+        promises.push(tokio::spawn(async move {
+            socks_server.start(bind).await.expect("SOCKS5 server failed running");
+        }));
+    }
+
     futures::future::join_all(promises).await;
     Ok(())
 }
-
-// TODO ip spoofing
-
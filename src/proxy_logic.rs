@@ -1,50 +1,357 @@
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
 use regex::Regex;
-use reqwest::StatusCode;
+use tokio::net::TcpStream;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_LENGTH, CONTENT_TYPE, HOST, SET_COOKIE};
+use reqwest::redirect::{Attempt, Policy};
+use reqwest::{Client, Method, Response, StatusCode, Url};
+
+use crate::config::{CacheConfig, PolicyConfig, ProxyConfig};
+use crate::destination_filter::{DestinationDenied, DestinationFilter, FilteringResolver};
+use crate::proxy_request::ProxyRequest;
+use crate::proxy_response::{ProxyResponse, PROXY_CACHE_HEADER};
+use crate::proxy_response_stream::ProxyResponseStream;
+use crate::cache::response_cache::{CacheLookup, ResponseCache, CACHE_BYPASS, CACHE_MISS};
+use crate::url_policy::{PolicyViolation, UrlPolicy};
+
+/// Same as the default redirects policy of the HTTP client
+const MAX_REDIRECTS: usize = 10;
+
+/// Separates the request line and the headers from the body
+const BODY_SEPARATORS: &[&[u8]] = &[b"\n\n", b"\r\n\r\n"];
 
-pub struct ProxyLogic {}
+/// Shared between the TCP and UDP servers, so that they use the same upstream connections pool
+pub struct ProxyLogic {
+    config: ProxyConfig,
+    client: Client,
+    strip_headers: Vec<HeaderName>,
+    inject_headers: HeaderMap,
+    destination_filter: Arc<DestinationFilter>,
+    url_policy: Arc<UrlPolicy>,
+    cache: ResponseCache,
+}
 
 impl ProxyLogic {
-    pub fn process_message(message: &str) -> Result<String, String> {
-        let re = Regex::new(r"^GET:(?P<url>.+)$").unwrap();
-        println!("The message is {}", message);
-        let cap = re.captures(message);
+    /// The header names and values in the config are expected to be validated already
+    pub fn new(config: ProxyConfig, policy_config: PolicyConfig, cache_config: &CacheConfig) -> Result<Self, String> {
+        let strip_headers = config.strip_headers
+            .iter()
+            .map(|name| HeaderName::from_bytes(name.as_bytes()).expect("Header names are validated in the config"))
+            .collect();
+        let inject_headers = config.inject_headers
+            .iter()
+            .map(|(name, value)| (
+                HeaderName::from_bytes(name.as_bytes()).expect("Header names are validated in the config"),
+                HeaderValue::from_str(value).expect("Header values are validated in the config"),
+            ))
+            .collect();
+        let destination_filter = Arc::new(DestinationFilter::new(
+            config.denied_networks.clone(),
+            config.allowed_networks.clone(),
+        ));
+        let url_policy = Arc::new(UrlPolicy::new(policy_config));
+        Ok(ProxyLogic {
+            config,
+            client: Self::build_client(destination_filter.clone(), url_policy.clone()),
+            strip_headers,
+            inject_headers,
+            destination_filter,
+            url_policy,
+            cache: ResponseCache::new(cache_config)?,
+        })
+    }
+
+    /// All the connections are going through the destination filter and the URL policy,
+    /// including the redirects. The system proxy settings are ignored, as the filter can't check
+    /// where those would connect
+    fn build_client(destination_filter: Arc<DestinationFilter>, url_policy: Arc<UrlPolicy>) -> Client {
+        let redirects_filter = destination_filter.clone();
+        Client::builder()
+            .no_proxy()
+            .dns_resolver(Arc::new(FilteringResolver::new(destination_filter)))
+            .redirect(Policy::custom(move |attempt: Attempt| {
+                if attempt.previous().len() > MAX_REDIRECTS {
+                    return attempt.error("Too many redirects");
+                }
+                if let Err(e) = url_policy.check_parsed(attempt.url()) {
+                    return attempt.error(e);
+                }
+                match redirects_filter.check_url(attempt.url()) {
+                    Ok(()) => attempt.follow(),
+                    Err(e) => attempt.error(e),
+                }
+            }))
+            .build()
+            .expect("Failed building the HTTP client")
+    }
+
+    /// The message is `METHOD:URL`, optionally followed by `Name: value` header lines, then
+    /// optionally followed by an empty line and the request body. Only the head has to be UTF-8,
+    /// the body is taken as is, so that binary bodies go through untouched
+    pub fn process_message(message: &[u8]) -> Result<ProxyRequest, String> {
+        let re = Regex::new(r"^(?P<method>GET|POST|PUT|DELETE|HEAD|PATCH|OPTIONS):(?P<url>.+)$").unwrap();
+        let (head, body) = match Self::find_body_separator(message) {
+            Some((position, separator)) => (&message[..position], Some(message[position + separator.len()..].to_vec())),
+            None => (message, None),
+        };
+        let head = std::str::from_utf8(head)
+            .map_err(|e| format!("The request line and the headers should be UTF-8: {}", e))?;
+        println!("The message is {}", head);
+        let head = head.trim();
+        let (request_line, header_lines) = head.split_once('\n').unwrap_or((head, ""));
+        let cap = re.captures(request_line.trim());
         if let Some(capture) = cap {
-            Ok(capture["url"].to_owned())
+            Ok(ProxyRequest {
+                method: Method::from_bytes(capture["method"].as_bytes()).unwrap(),
+                url: capture["url"].to_owned(),
+                headers: Self::parse_headers(header_lines)?,
+                body,
+            })
         } else {
-            Err("Invalid message structure! Use GET:URL format.\n".to_owned())
+            Err("Invalid message structure! Use METHOD:URL format, optionally followed by header lines, an empty line and the body.\n".to_owned())
+        }
+    }
+
+    /// The first empty line ends the head, with either line ending
+    fn find_body_separator(message: &[u8]) -> Option<(usize, &'static [u8])> {
+        BODY_SEPARATORS
+            .iter()
+            .filter_map(|separator| {
+                message
+                    .windows(separator.len())
+                    .position(|window| window == *separator)
+                    .map(|position| (position, *separator))
+            })
+            .min_by_key(|(position, _)| *position)
+    }
+
+    /// The `Host` header is dropped, as the target is in the URL, and the HTTP client would send
+    /// the given one as is, reaching another virtual host than the policy checked
+    fn parse_headers(header_lines: &str) -> Result<HeaderMap, String> {
+        let mut headers = HeaderMap::new();
+        for line in header_lines.lines() {
+            let (name, value) = line
+                .split_once(':')
+                .ok_or_else(|| format!("Invalid header line {}, use `Name: value` format", line))?;
+            let name = HeaderName::from_bytes(name.trim().as_bytes())
+                .map_err(|e| format!("Invalid header name {}: {}", name, e))?;
+            let value = HeaderValue::from_str(value.trim())
+                .map_err(|e| format!("Invalid value of the header {}: {}", name, e))?;
+            if name != HOST {
+                headers.append(name, value);
+            }
+        }
+        Ok(headers)
+    }
+
+    /// Returns the response envelope with the status, headers and body of the target server
+    pub async fn generate_content_to_send(&self, request: &ProxyRequest) -> Result<Vec<u8>, String> {
+        self.fetch(request).await?.collect().await
+    }
+
+    /// Same as `generate_content_to_send`, but the body is read from the target server only
+    /// while the caller is consuming the chunks
+    pub async fn fetch(&self, request: &ProxyRequest) -> Result<ProxyResponseStream, String> {
+        match self.execute(request).await {
+            Ok(stream) => Ok(stream),
+            Err(e) => match Self::refusal_response(e.as_ref()) {
+                Some(response) => {
+                    println!("Refused request to {}: {}", request.url, e);
+                    Ok(ProxyResponseStream::buffered(response))
+                }
+                None => Err(e.to_string()),
+            },
+        }
+    }
+
+    /// The refusals can come wrapped into the HTTP client errors, from the resolver or the redirects
+    fn refusal_response(error: &(dyn Error + 'static)) -> Option<ProxyResponse> {
+        let mut current = Some(error);
+        while let Some(e) = current {
+            if let Some(denied) = e.downcast_ref::<DestinationDenied>() {
+                return Some(ProxyResponse::proxy_error(StatusCode::FORBIDDEN, "destination-denied", denied.to_string()));
+            }
+            if let Some(violation) = e.downcast_ref::<PolicyViolation>() {
+                let status = match violation.reason_code {
+                    "url-invalid" | "url-too-long" => StatusCode::BAD_REQUEST,
+                    _ => StatusCode::FORBIDDEN,
+                };
+                return Some(ProxyResponse::proxy_error(status, violation.reason_code, violation.message.clone()));
+            }
+            current = e.source();
+        }
+        None
+    }
+
+    async fn execute(&self, request: &ProxyRequest) -> Result<ProxyResponseStream, Box<dyn Error + Send + Sync>> {
+        let url = &request.url;
+        println!("The request is {} {}", request.method, url);
+        let parsed_url = self.url_policy.check(url)?;
+        self.destination_filter.check_url(&parsed_url)?;
+        let mut headers = request.headers.clone();
+        self.strip(&mut headers);
+        for (name, value) in self.inject_headers.iter() {
+            headers.insert(name, value.clone());
+        }
+        let cacheable = self.cache.is_cacheable(request);
+        if !request.method.is_safe() {
+            self.cache.invalidate(parsed_url.as_str()).await;
+        }
+        let mut validators = None;
+        if cacheable {
+            match self.cache.lookup(parsed_url.as_str(), &headers).await {
+                CacheLookup::Fresh(response) => return Ok(ProxyResponseStream::buffered(response)),
+                CacheLookup::Stale(conditional) => validators = Some(conditional),
+                CacheLookup::Miss => {}
+            }
+        }
+
+        let mut result = match &validators {
+            Some(validators) => {
+                let mut conditional_headers = headers.clone();
+                conditional_headers.extend(validators.clone());
+                self.send(request, &parsed_url, conditional_headers).await?
+            }
+            None => self.send(request, &parsed_url, headers.clone()).await?,
+        };
+        if validators.is_some() && result.status() == StatusCode::NOT_MODIFIED {
+            let mut not_modified_headers = result.headers().clone();
+            self.strip(&mut not_modified_headers);
+            match self.cache.revalidate(parsed_url.as_str(), &headers, &not_modified_headers).await {
+                Some(mut response) => {
+                    // The cookies aren't stored, but the revalidating client still gets them
+                    for cookie in not_modified_headers.get_all(SET_COOKIE) {
+                        response.headers.append(SET_COOKIE, cookie.clone());
+                    }
+                    return Ok(ProxyResponseStream::buffered(response));
+                }
+                // The client didn't ask for a conditional response, so asking for the whole one
+                None => result = self.send(request, &parsed_url, headers.clone()).await?,
+            }
+        }
+
+        let mut response = ProxyResponse {
+            status: result.status(),
+            headers: result.headers().clone(),
+            body: Vec::new(),
+        };
+        self.strip(&mut response.headers);
+        if self.cache.is_enabled() {
+            let cache_status = if cacheable { CACHE_MISS } else { CACHE_BYPASS };
+            response.headers.insert(PROXY_CACHE_HEADER, HeaderValue::from_static(cache_status));
+        }
+        if self.config.html_error_pages && !response.status.is_success() {
+            Self::replace_with_error_page(&mut response, url);
+            return Ok(ProxyResponseStream::buffered(response));
+        }
+        // Only the responses of known size are cached, so that they can be read into memory
+        // before being passed on. The redirected ones belong to another URL
+        let fits_cache = result.url() == &parsed_url && result
+            .content_length()
+            .is_some_and(|length| length as usize <= self.cache.max_entry_size());
+        if cacheable && fits_cache {
+            response.body = result.bytes().await?.to_vec();
+            self.cache.store(parsed_url.as_str(), &headers, &response, response.body.clone()).await;
+            return Ok(ProxyResponseStream::buffered(response));
+        }
+        Ok(ProxyResponseStream::streaming(response, result))
+    }
+
+    async fn send(&self, request: &ProxyRequest, url: &Url, headers: HeaderMap) -> Result<Response, reqwest::Error> {
+        let mut request_builder = self.client
+            .request(request.method.clone(), url.clone())
+            .headers(headers);
+        if let Some(body) = &request.body {
+            request_builder = request_builder.body(body.clone());
         }
+        request_builder.send().await
     }
 
-    pub async fn generate_content_to_send(url: &str) -> Result<Vec<u8>, String> {
-        println!("The url is {}", url);
-        let result = reqwest::get(url)
+    /// Connecting to the target of a tunnel, going through the same URL policy and destination
+    /// filter as the fetched URLs. The refusals are returned as the responses to pass to the client
+    pub async fn open_tunnel(&self, host: &str, port: u16) -> Result<TcpStream, ProxyResponse> {
+        println!("The tunnel is to {}:{}", host, port);
+        let addrs = self.resolve_allowed(host, port).await?;
+        TcpStream::connect(addrs.as_slice())
             .await
-            .map_err(|e| e.to_string())?;
-        if result.status() == StatusCode::OK {
-            Ok(result.bytes()
-                .await
-                .map_err(|e| e.to_string())?
-                .to_vec())
-        } else {
-            let generated_response = format!(
-                r#"
-                <html lang="en">
-                    <head>
-                        <meta charset="UTF-8">
-                        <meta http-equiv="X-UA-Compatible" content="IE=edge">
-                        <meta name="viewport" content="width=device-width, initial-scale=1.0">
-                        <title>Error {}</title>
-                    </head>
-                    <body>
-                        <div style="position: absolute;top: 50%;left: 50%;transform: translate(-50%, -50%);">Received {} error from {} url</div>
-                    </body>
-                </html>
-                "#,
-                result.status(),
-                result.status(),
-                url
-            );
-            Ok(generated_response.into_bytes())
-        }
-    }
-}
\ No newline at end of file
+            .map_err(|e| Self::unreachable(host, port, e))
+    }
+
+    /// The addresses of the host which the policy and the destination filter allow connecting to
+    pub async fn resolve_allowed(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>, ProxyResponse> {
+        if let Err(violation) = self.url_policy.check_host_port(host, Some(port)) {
+            return Err(ProxyResponse::proxy_error(StatusCode::FORBIDDEN, violation.reason_code, violation.message));
+        }
+        let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.trim_start_matches('[').trim_end_matches(']'), port))
+            .await
+            .map_err(|e| Self::unreachable(host, port, e))?
+            .filter(|addr| self.destination_filter.is_allowed(addr.ip()))
+            .collect();
+        if addrs.is_empty() {
+            let denied = DestinationDenied { host: host.to_owned() };
+            return Err(ProxyResponse::proxy_error(StatusCode::FORBIDDEN, "destination-denied", denied.to_string()));
+        }
+        Ok(addrs)
+    }
+
+    fn unreachable(host: &str, port: u16, error: std::io::Error) -> ProxyResponse {
+        ProxyResponse::proxy_error(
+            StatusCode::BAD_GATEWAY,
+            "connect-failed",
+            format!("Failed connecting to {}:{}: {}", host, port, error),
+        )
+    }
+
+    fn strip(&self, headers: &mut HeaderMap) {
+        for name in self.strip_headers.iter() {
+            headers.remove(name);
+        }
+    }
+
+    fn replace_with_error_page(response: &mut ProxyResponse, url: &str) {
+        let generated_response = format!(
+            r#"
+            <html lang="en">
+                <head>
+                    <meta charset="UTF-8">
+                    <meta http-equiv="X-UA-Compatible" content="IE=edge">
+                    <meta name="viewport" content="width=device-width, initial-scale=1.0">
+                    <title>Error {}</title>
+                </head>
+                <body>
+                    <div style="position: absolute;top: 50%;left: 50%;transform: translate(-50%, -50%);">Received {} error from {} url</div>
+                </body>
+            </html>
+            "#,
+            response.status,
+            response.status,
+            url
+        );
+        response.body = generated_response.into_bytes();
+        response.headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/html; charset=utf-8"));
+        response.headers.insert(CONTENT_LENGTH, HeaderValue::from(response.body.len()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn headers_and_body_are_parsed() {
+        let request = ProxyLogic::process_message(b"POST:http://example.com/\r\nAccept: text/plain\r\n\r\n\x00\xff").unwrap();
+        assert_eq!(request.method, Method::POST);
+        assert_eq!(request.url, "http://example.com/");
+        assert_eq!(request.headers["accept"], "text/plain");
+        assert_eq!(request.body, Some(vec![0x00, 0xff]));
+    }
+
+    #[test]
+    fn host_header_is_dropped() {
+        let request = ProxyLogic::process_message(b"GET:https://allowed.example/\nHost: internal.corp\nAccept: */*").unwrap();
+        assert!(!request.headers.contains_key(HOST));
+        assert_eq!(request.headers["accept"], "*/*");
+    }
+}
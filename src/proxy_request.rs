@@ -0,0 +1,10 @@
+use reqwest::header::HeaderMap;
+use reqwest::Method;
+
+/// Request to be executed by the proxy, parsed from the client message
+pub struct ProxyRequest {
+    pub method: Method,
+    pub url: String,
+    pub headers: HeaderMap,
+    pub body: Option<Vec<u8>>,
+}
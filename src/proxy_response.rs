@@ -0,0 +1,46 @@
+use reqwest::header::{HeaderMap, HeaderValue, CONTENT_TYPE};
+use reqwest::StatusCode;
+
+/// Separates the status line and the headers from the body
+const ENVELOPE_BODY_SEPARATOR: &[u8] = b"\n\n";
+/// Set on the responses generated by the proxy itself, carrying the machine-readable reason
+pub const PROXY_ERROR_HEADER: &str = "x-proxy-error";
+/// Set on the responses to the requests the cache is enabled for, telling whether it was used
+pub const PROXY_CACHE_HEADER: &str = "x-proxy-cache";
+
+/// Response of the target server, passed back to the client as is
+pub struct ProxyResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+}
+
+impl ProxyResponse {
+    /// Response generated by the proxy when it refuses the request, so that the clients can tell
+    /// it apart from the target server responses
+    pub fn proxy_error(status: StatusCode, reason_code: &'static str, message: String) -> Self {
+        let mut headers = HeaderMap::new();
+        headers.insert(PROXY_ERROR_HEADER, HeaderValue::from_static(reason_code));
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/plain; charset=utf-8"));
+        ProxyResponse {
+            status,
+            headers,
+            body: message.into_bytes(),
+        }
+    }
+
+    /// The envelope is the status line (e.g. `404 Not Found`), then a `Name: value` line per
+    /// header, then an empty line and the raw body. This is everything before the body
+    pub fn envelope_head(&self) -> Vec<u8> {
+        let mut envelope = Vec::new();
+        envelope.extend(self.status.to_string().as_bytes());
+        for (name, value) in self.headers.iter() {
+            envelope.push(b'\n');
+            envelope.extend(name.as_str().as_bytes());
+            envelope.extend(b": ");
+            envelope.extend(value.as_bytes());
+        }
+        envelope.extend(ENVELOPE_BODY_SEPARATOR);
+        envelope
+    }
+}
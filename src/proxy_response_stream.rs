@@ -0,0 +1,87 @@
+use reqwest::header::HeaderMap;
+use reqwest::{Response, StatusCode};
+
+use crate::proxy_response::ProxyResponse;
+
+/// Response envelope which is produced piece by piece, so that large bodies don't have to be
+/// kept in memory. The first chunk is the envelope head, then the body chunks follow
+pub struct ProxyResponseStream {
+    /// The status and the headers, and the body when it's already in memory
+    response: ProxyResponse,
+    head_sent: bool,
+    upstream: Option<Response>,
+}
+
+impl ProxyResponseStream {
+    /// For the responses which are already in memory, such as the ones generated by the proxy
+    pub fn buffered(response: ProxyResponse) -> Self {
+        ProxyResponseStream {
+            response,
+            head_sent: false,
+            upstream: None,
+        }
+    }
+
+    /// The body is read from the upstream response while the chunks are requested
+    pub fn streaming(head: ProxyResponse, upstream: Response) -> Self {
+        ProxyResponseStream {
+            response: head,
+            head_sent: false,
+            upstream: Some(upstream),
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.response.status
+    }
+
+    pub fn headers(&self) -> &HeaderMap {
+        &self.response.headers
+    }
+
+    /// Returns `None` after the last chunk
+    pub async fn next_chunk(&mut self) -> Result<Option<Vec<u8>>, String> {
+        if !self.head_sent {
+            self.head_sent = true;
+            return Ok(Some(self.response.envelope_head()));
+        }
+        self.next_body_chunk().await
+    }
+
+    /// Same as `next_chunk`, but without the envelope head, for the transports which send the
+    /// status and the headers in their own way
+    pub async fn next_body_chunk(&mut self) -> Result<Option<Vec<u8>>, String> {
+        self.head_sent = true;
+        if !self.response.body.is_empty() {
+            return Ok(Some(std::mem::take(&mut self.response.body)));
+        }
+        match self.upstream.as_mut() {
+            Some(upstream) => {
+                let chunk = upstream
+                    .chunk()
+                    .await
+                    .map_err(|e| format!("Failed reading the response body: {}", e))?;
+                Ok(chunk.map(|c| c.to_vec()))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Reading the whole envelope into memory, for the transports which can't send it in pieces
+    pub async fn collect(self) -> Result<Vec<u8>, String> {
+        self.collect_at_most(usize::MAX).await
+    }
+
+    /// Same as `collect`, but the reading stops with a failure once the envelope gets bigger
+    /// than the given size
+    pub async fn collect_at_most(mut self, max_size: usize) -> Result<Vec<u8>, String> {
+        let mut envelope = Vec::new();
+        while let Some(chunk) = self.next_chunk().await? {
+            if envelope.len() + chunk.len() > max_size {
+                return Err(format!("Response is bigger than {} bytes", max_size));
+            }
+            envelope.extend(chunk);
+        }
+        Ok(envelope)
+    }
+}
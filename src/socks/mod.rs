@@ -0,0 +1,3 @@
+pub mod socks_address;
+pub mod socks_server;
+pub mod socks_udp_relay;
@@ -0,0 +1,112 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const IPV4_TYPE: u8 = 1;
+const DOMAIN_TYPE: u8 = 3;
+const IPV6_TYPE: u8 = 4;
+
+/// Address in the SOCKS5 requests and replies, the type byte followed by the address and the port
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub enum SocksAddress {
+    Ip(SocketAddr),
+    Domain(String, u16),
+}
+
+impl SocksAddress {
+    /// Reading the address of a request from the control connection
+    pub async fn read_from<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Self, String> {
+        let failure = |e: std::io::Error| format!("Failed reading the SOCKS address: {}", e);
+        let address_type = reader.read_u8().await.map_err(failure)?;
+        let mut address = vec![address_type];
+        match address_type {
+            IPV4_TYPE => address.resize(1 + 4 + 2, 0),
+            IPV6_TYPE => address.resize(1 + 16 + 2, 0),
+            DOMAIN_TYPE => {
+                let length = reader.read_u8().await.map_err(failure)?;
+                address.push(length);
+                address.resize(2 + length as usize + 2, 0);
+            }
+            other => return Err(format!("Unknown SOCKS address type {}", other)),
+        }
+        let start = if address_type == DOMAIN_TYPE { 2 } else { 1 };
+        reader.read_exact(&mut address[start..]).await.map_err(failure)?;
+        Self::parse(&address).map(|(address, _)| address)
+    }
+
+    /// Returns the address and the number of bytes it took, as the UDP datagrams carry the data
+    /// right after it
+    pub fn parse(bytes: &[u8]) -> Result<(Self, usize), String> {
+        let truncated = || "Truncated SOCKS address".to_owned();
+        let (&address_type, rest) = bytes.split_first().ok_or_else(truncated)?;
+        let (address, length) = match address_type {
+            IPV4_TYPE => {
+                let octets: [u8; 4] = rest.get(..4).ok_or_else(truncated)?.try_into().unwrap();
+                (IpAddr::V4(Ipv4Addr::from(octets)).to_string(), 4)
+            }
+            IPV6_TYPE => {
+                let octets: [u8; 16] = rest.get(..16).ok_or_else(truncated)?.try_into().unwrap();
+                (IpAddr::V6(Ipv6Addr::from(octets)).to_string(), 16)
+            }
+            DOMAIN_TYPE => {
+                let length = *rest.first().ok_or_else(truncated)? as usize;
+                let domain = rest.get(1..1 + length).ok_or_else(truncated)?;
+                let domain = String::from_utf8(domain.to_vec()).map_err(|_| "Invalid SOCKS domain".to_owned())?;
+                (domain, 1 + length)
+            }
+            other => return Err(format!("Unknown SOCKS address type {}", other)),
+        };
+        let port_bytes: [u8; 2] = rest.get(length..length + 2).ok_or_else(truncated)?.try_into().unwrap();
+        let port = u16::from_be_bytes(port_bytes);
+        let parsed = match address_type {
+            DOMAIN_TYPE => SocksAddress::Domain(address, port),
+            _ => SocksAddress::Ip(SocketAddr::new(address.parse().unwrap(), port)),
+        };
+        Ok((parsed, 1 + length + 2))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        match self {
+            SocksAddress::Ip(SocketAddr::V4(addr)) => {
+                bytes.push(IPV4_TYPE);
+                bytes.extend(addr.ip().octets());
+            }
+            SocksAddress::Ip(SocketAddr::V6(addr)) => {
+                bytes.push(IPV6_TYPE);
+                bytes.extend(addr.ip().octets());
+            }
+            SocksAddress::Domain(domain, _) => {
+                bytes.push(DOMAIN_TYPE);
+                bytes.push(domain.len() as u8);
+                bytes.extend(domain.as_bytes());
+            }
+        }
+        bytes.extend(self.port().to_be_bytes());
+        bytes
+    }
+
+    /// In the same form as the URL hosts, so that the policy treats them the same way
+    pub fn host(&self) -> String {
+        match self {
+            SocksAddress::Ip(SocketAddr::V4(addr)) => addr.ip().to_string(),
+            SocksAddress::Ip(SocketAddr::V6(addr)) => format!("[{}]", addr.ip()),
+            SocksAddress::Domain(domain, _) => domain.clone(),
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        match self {
+            SocksAddress::Ip(addr) => addr.port(),
+            SocksAddress::Domain(_, port) => *port,
+        }
+    }
+
+    /// Unspecified when the client doesn't know yet where it will send the datagrams from
+    pub fn is_unspecified(&self) -> bool {
+        match self {
+            SocksAddress::Ip(addr) => addr.ip().is_unspecified() || addr.port() == 0,
+            SocksAddress::Domain(_, port) => *port == 0,
+        }
+    }
+}
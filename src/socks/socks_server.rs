@@ -0,0 +1,232 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use reqwest::StatusCode;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::Semaphore;
+use tokio::time::timeout;
+
+use crate::authenticator::Authenticator;
+use crate::config::SocksConfig;
+use crate::proxy_logic::ProxyLogic;
+use crate::proxy_response::ProxyResponse;
+use crate::tunnel_relay::TunnelRelay;
+
+use super::socks_address::SocksAddress;
+use super::socks_udp_relay::SocksUdpRelay;
+
+const SOCKS_VERSION: u8 = 5;
+const NO_AUTHENTICATION: u8 = 0;
+const USERNAME_PASSWORD: u8 = 2;
+const NO_ACCEPTABLE_METHODS: u8 = 0xFF;
+/// Version of the username and password subnegotiation, RFC 1929
+const USERNAME_PASSWORD_VERSION: u8 = 1;
+
+const CONNECT_COMMAND: u8 = 1;
+const UDP_ASSOCIATE_COMMAND: u8 = 3;
+
+const SUCCEEDED: u8 = 0;
+const GENERAL_FAILURE: u8 = 1;
+const NOT_ALLOWED: u8 = 2;
+const HOST_UNREACHABLE: u8 = 4;
+const COMMAND_NOT_SUPPORTED: u8 = 7;
+
+/// SOCKS5 frontend, serving CONNECT with the same tunnels as the HTTP proxy and UDP ASSOCIATE
+/// with a relay per client. When the authentication is enabled, the clients log in with their
+/// name and token as the username and password, otherwise no authentication is asked
+pub struct SocksServer {
+    proxy_logic: Arc<ProxyLogic>,
+    authenticator: Arc<Authenticator>,
+    /// The client has to send its request during this period after connecting
+    handshake_timeout: Duration,
+    /// The tunnels and the UDP relays are closed once idle for this period
+    idle_timeout: Duration,
+    max_connections: usize,
+}
+
+impl SocksServer {
+    /// Taking the timeouts and the limits from the config
+    pub fn new(proxy_logic: Arc<ProxyLogic>, authenticator: Arc<Authenticator>, config: &SocksConfig) -> Self {
+        SocksServer {
+            proxy_logic,
+            authenticator,
+            handshake_timeout: config.handshake_timeout(),
+            idle_timeout: config.idle_timeout(),
+            max_connections: config.max_connections,
+        }
+    }
+
+    pub async fn start(self, bind: SocketAddr) -> Result<(), String> {
+        println!("Starting the SOCKS5 server...");
+        let listener = TcpListener::bind(bind)
+            .await
+            .map_err(|e| format!("Failed binding the SOCKS5 listener to {}: {}", bind, e))?;
+        let connections = Arc::new(Semaphore::new(self.max_connections));
+        let server = Arc::new(self);
+        loop {
+            let permit = connections
+                .clone()
+                .acquire_owned()
+                .await
+                .expect("The connections semaphore is never closed");
+            let (stream, peer) = listener
+                .accept()
+                .await
+                .map_err(|e| format!("SOCKS5 listener failed accepting: {}", e))?;
+            let server = server.clone();
+            tokio::spawn(async move {
+                if let Err(e) = server.handle_client(stream, peer).await {
+                    println!("SOCKS5 client {} failed: {}", peer, e);
+                }
+                drop(permit);
+            });
+        }
+    }
+
+    async fn handle_client(&self, mut stream: TcpStream, peer: SocketAddr) -> Result<(), String> {
+        let (command, address) = timeout(self.handshake_timeout, self.read_request(&mut stream, peer))
+            .await
+            .map_err(|_| format!("Handshake didn't complete in {} seconds", self.handshake_timeout.as_secs()))??;
+        match command {
+            CONNECT_COMMAND => self.connect(stream, peer, address).await,
+            UDP_ASSOCIATE_COMMAND => self.associate(stream, peer, address).await,
+            other => {
+                Self::reply(&mut stream, COMMAND_NOT_SUPPORTED, None).await?;
+                Err(format!("Unsupported SOCKS command {}", other))
+            }
+        }
+    }
+
+    /// The method selection, the authentication and the request, returns the command and its address
+    async fn read_request(&self, stream: &mut TcpStream, peer: SocketAddr) -> Result<(u8, SocksAddress), String> {
+        let failure = |e: std::io::Error| format!("Failed reading the SOCKS handshake: {}", e);
+        let mut greeting = [0; 2];
+        stream.read_exact(&mut greeting).await.map_err(failure)?;
+        if greeting[0] != SOCKS_VERSION {
+            return Err(format!("Unsupported SOCKS version {}", greeting[0]));
+        }
+        let mut methods = vec![0; greeting[1] as usize];
+        stream.read_exact(&mut methods).await.map_err(failure)?;
+        let required = if self.authenticator.is_enabled() { USERNAME_PASSWORD } else { NO_AUTHENTICATION };
+        let method = if methods.contains(&required) { required } else { NO_ACCEPTABLE_METHODS };
+        Self::write(stream, &[SOCKS_VERSION, method]).await?;
+        match method {
+            NO_ACCEPTABLE_METHODS => return Err("No acceptable authentication method offered".to_owned()),
+            USERNAME_PASSWORD => self.authenticate(stream, peer).await?,
+            _ => {}
+        }
+
+        let mut request = [0; 3];
+        stream.read_exact(&mut request).await.map_err(failure)?;
+        if request[0] != SOCKS_VERSION {
+            return Err(format!("Unsupported SOCKS version {}", request[0]));
+        }
+        let address = match SocksAddress::read_from(stream).await {
+            Ok(address) => address,
+            Err(e) => {
+                Self::reply(stream, GENERAL_FAILURE, None).await?;
+                return Err(e);
+            }
+        };
+        Ok((request[1], address))
+    }
+
+    /// The username is the client name and the password is its token
+    async fn authenticate(&self, stream: &mut TcpStream, peer: SocketAddr) -> Result<(), String> {
+        let failure = |e: std::io::Error| format!("Failed reading the SOCKS credentials: {}", e);
+        let version = stream.read_u8().await.map_err(failure)?;
+        if version != USERNAME_PASSWORD_VERSION {
+            return Err(format!("Unsupported SOCKS authentication version {}", version));
+        }
+        let mut name = vec![0; stream.read_u8().await.map_err(failure)? as usize];
+        stream.read_exact(&mut name).await.map_err(failure)?;
+        let mut token = vec![0; stream.read_u8().await.map_err(failure)? as usize];
+        stream.read_exact(&mut token).await.map_err(failure)?;
+        let name = String::from_utf8_lossy(&name);
+        let authenticated = self.authenticator
+            .check_token(&String::from_utf8_lossy(&token))
+            .is_ok_and(|owner| owner == name);
+        Self::write(stream, &[USERNAME_PASSWORD_VERSION, if authenticated { 0 } else { 1 }]).await?;
+        if !authenticated {
+            return Err(format!("Invalid credentials of {}", name));
+        }
+        println!("SOCKS5 client {} authenticated as {}", peer, name);
+        Ok(())
+    }
+
+    async fn connect(&self, mut stream: TcpStream, peer: SocketAddr, address: SocksAddress) -> Result<(), String> {
+        let target = match self.proxy_logic.open_tunnel(&address.host(), address.port()).await {
+            Ok(target) => target,
+            Err(response) => {
+                Self::reply(&mut stream, Self::failure_reply(&response), None).await?;
+                return Err(String::from_utf8_lossy(&response.body).into_owned());
+            }
+        };
+        let bound = target.local_addr().ok();
+        Self::reply(&mut stream, SUCCEEDED, bound).await?;
+        let (sent, received) = TunnelRelay::relay(stream.into_split(), target.into_split(), self.idle_timeout)
+            .await
+            .map_err(|e| format!("Tunnel to {}:{} failed: {}", address.host(), address.port(), e))?;
+        println!(
+            "Tunnel of {} to {}:{} closed after sending {} and receiving {} bytes",
+            peer, address.host(), address.port(), sent, received
+        );
+        Ok(())
+    }
+
+    /// The relay lives as long as the control connection, which carries nothing else
+    async fn associate(&self, mut stream: TcpStream, peer: SocketAddr, address: SocksAddress) -> Result<(), String> {
+        let local_ip = stream
+            .local_addr()
+            .map_err(|e| format!("Failed getting the SOCKS listener address: {}", e))?
+            .ip();
+        let socket = match UdpSocket::bind(SocketAddr::new(local_ip, 0)).await {
+            Ok(socket) => socket,
+            Err(e) => {
+                Self::reply(&mut stream, GENERAL_FAILURE, None).await?;
+                return Err(format!("Failed binding the SOCKS UDP relay: {}", e));
+            }
+        };
+        let relay_addr = socket.local_addr().ok();
+        // Another host named by the client would get the answers of the targets, so it's only
+        // trusted on the host of the control connection, and learnt from the first datagram otherwise
+        let client = match address {
+            SocksAddress::Ip(addr) if !address.is_unspecified() && addr.ip() == peer.ip() => Some(addr),
+            _ => None,
+        };
+        Self::reply(&mut stream, SUCCEEDED, relay_addr).await?;
+        println!("SOCKS5 client {} got the UDP relay {:?}", peer, relay_addr);
+        let relay = SocksUdpRelay::new(socket, self.proxy_logic.clone(), peer.ip(), client, self.idle_timeout);
+        let mut control = [0; 1];
+        tokio::select! {
+            result = relay.run() => result,
+            _ = stream.read(&mut control) => {
+                println!("SOCKS5 client {} closed the UDP association", peer);
+                Ok(())
+            }
+        }
+    }
+
+    fn failure_reply(response: &ProxyResponse) -> u8 {
+        match response.status {
+            StatusCode::FORBIDDEN => NOT_ALLOWED,
+            _ => HOST_UNREACHABLE,
+        }
+    }
+
+    async fn reply(stream: &mut TcpStream, code: u8, bound: Option<SocketAddr>) -> Result<(), String> {
+        let bound = bound.unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0)));
+        let mut reply = vec![SOCKS_VERSION, code, 0];
+        reply.extend(SocksAddress::Ip(bound).to_bytes());
+        Self::write(stream, &reply).await
+    }
+
+    async fn write(stream: &mut TcpStream, bytes: &[u8]) -> Result<(), String> {
+        stream
+            .write_all(bytes)
+            .await
+            .map_err(|e| format!("Failed writing the SOCKS reply: {}", e))
+    }
+}
@@ -0,0 +1,126 @@
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+
+use crate::proxy_logic::ProxyLogic;
+
+use super::socks_address::SocksAddress;
+
+/// Reserved bytes and the fragment number before the address in every datagram
+const DATAGRAM_PREFIX: [u8; 3] = [0, 0, 0];
+/// Largest possible UDP payload
+const MAX_DATAGRAM_SIZE: usize = 65535;
+/// Bounding the memory of a single association, the client can't make the relay remember
+/// every host it ever sent to
+const MAX_TARGETS: usize = 256;
+
+/// Relaying the datagrams of a UDP ASSOCIATE request. The client sends them with the SOCKS
+/// header naming the target, and gets the answers of the targets back with the header naming
+/// the sender. Only the targets the client sent to are relayed back, and the targets go
+/// through the same policy and destination filter as the tunnels
+pub struct SocksUdpRelay {
+    socket: UdpSocket,
+    proxy_logic: Arc<ProxyLogic>,
+    /// The datagrams are only accepted from the host of the control connection
+    client_ip: IpAddr,
+    /// Learnt from the first datagram when the client didn't tell it in the request, always on
+    /// the host of the control connection
+    client: Option<SocketAddr>,
+    resolved: HashMap<SocksAddress, SocketAddr>,
+    contacted: HashSet<SocketAddr>,
+    /// The relay is closed once nothing comes in either direction during this period
+    idle_timeout: Duration,
+}
+
+impl SocksUdpRelay {
+    pub fn new(socket: UdpSocket, proxy_logic: Arc<ProxyLogic>, client_ip: IpAddr, client: Option<SocketAddr>, idle_timeout: Duration) -> Self {
+        SocksUdpRelay {
+            socket,
+            proxy_logic,
+            client_ip,
+            client,
+            resolved: HashMap::new(),
+            contacted: HashSet::new(),
+            idle_timeout,
+        }
+    }
+
+    /// Runs until the socket fails, the caller stops it when the control connection is closed
+    pub async fn run(mut self) -> Result<(), String> {
+        let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
+        loop {
+            let (length, sender) = timeout(self.idle_timeout, self.socket.recv_from(&mut buffer))
+                .await
+                .map_err(|_| format!("SOCKS UDP relay was idle for more than {} seconds", self.idle_timeout.as_secs()))?
+                .map_err(|e| format!("SOCKS UDP relay failed receiving: {}", e))?;
+            let datagram = &buffer[..length];
+            let result = if self.contacted.contains(&sender) {
+                self.relay_to_client(sender, datagram).await
+            } else if self.is_client(sender) {
+                self.client = Some(sender);
+                self.relay_to_target(datagram).await
+            } else {
+                Err(format!("Dropped SOCKS datagram from unknown sender {}", sender))
+            };
+            if let Err(e) = result {
+                println!("{}", e);
+            }
+        }
+    }
+
+    fn is_client(&self, sender: SocketAddr) -> bool {
+        match self.client {
+            Some(client) => client == sender,
+            None => sender.ip() == self.client_ip,
+        }
+    }
+
+    async fn relay_to_target(&mut self, datagram: &[u8]) -> Result<(), String> {
+        if datagram.len() < DATAGRAM_PREFIX.len() {
+            return Err("Truncated SOCKS datagram".to_owned());
+        }
+        if datagram[2] != 0 {
+            return Err("Fragmented SOCKS datagrams are not supported".to_owned());
+        }
+        let (address, address_length) = SocksAddress::parse(&datagram[DATAGRAM_PREFIX.len()..])?;
+        let payload = &datagram[DATAGRAM_PREFIX.len() + address_length..];
+        let target = self.resolve(&address).await?;
+        self.socket
+            .send_to(payload, target)
+            .await
+            .map_err(|e| format!("Failed relaying SOCKS datagram to {}: {}", target, e))?;
+        Ok(())
+    }
+
+    async fn resolve(&mut self, address: &SocksAddress) -> Result<SocketAddr, String> {
+        if let Some(target) = self.resolved.get(address) {
+            return Ok(*target);
+        }
+        if self.resolved.len() >= MAX_TARGETS {
+            return Err(format!("SOCKS UDP association reached {} targets", MAX_TARGETS));
+        }
+        let target = self.proxy_logic
+            .resolve_allowed(&address.host(), address.port())
+            .await
+            .map_err(|response| format!("Refused SOCKS datagram to {}:{}: {}", address.host(), address.port(), String::from_utf8_lossy(&response.body)))?[0];
+        self.resolved.insert(address.clone(), target);
+        self.contacted.insert(target);
+        Ok(target)
+    }
+
+    async fn relay_to_client(&self, sender: SocketAddr, payload: &[u8]) -> Result<(), String> {
+        let client = self.client.expect("Targets are contacted only after the client is known");
+        let mut datagram = DATAGRAM_PREFIX.to_vec();
+        datagram.extend(SocksAddress::Ip(sender).to_bytes());
+        datagram.extend(payload);
+        self.socket
+            .send_to(&datagram, client)
+            .await
+            .map_err(|e| format!("Failed relaying SOCKS datagram to {}: {}", client, e))?;
+        Ok(())
+    }
+}
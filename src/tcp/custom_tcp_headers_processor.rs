@@ -1,25 +1,65 @@
-pub const HEADERS_LENGTH: usize = 4;
+pub const HEADERS_LENGTH: usize = 1 + 1 + 4 + 8 + 4;
+/// Changed whenever the frame layout changes, so that the peers can detect the incompatibility
+pub const FRAME_VERSION: u8 = 1;
+/// Set on all the frames of a chunked response except the last one
+pub const MORE_FRAMES_FLAG: u8 = 1;
+/// Set on the frame which is reporting a failure instead of the response
+pub const ERROR_FLAG: u8 = 1 << 1;
+/// Set on the frame which content is compressed with the algorithm agreed during the greeting
+pub const COMPRESSED_FLAG: u8 = 1 << 2;
+
+pub struct FrameHeaders {
+    pub flags: u8,
+    pub request_id: u32,
+    pub length: u64,
+    pub checksum: u32,
+}
 
 pub struct CustomTcpHeadersProcessor {}
 
-/// First 4 bytes will be the header for showing the length of the content
+/// First byte will be the frame format version and the next byte will be the flags. Then 4 bytes
+/// for the request ID, so that several requests can be in flight on the same connection and the
+/// responses can be matched with them. Then 8 bytes for the length of the content and the last
+/// 4 bytes will be the CRC32 of the content.
+/// Large responses are split into several frames with the same request ID, which the client
+/// should concatenate
 impl CustomTcpHeadersProcessor {
-    pub fn parse_headers(message: Vec<u8>) -> (u32, Vec<u8>) {
-        (
-            u32::from_be_bytes([message[0], message[1], message[2], message[3]]),
-            message[4..].to_vec(),
-        )
+    /// Returns the parsed headers and the rest of the message
+    pub fn parse_headers(message: Vec<u8>) -> Result<(FrameHeaders, Vec<u8>), String> {
+        let version = message[0];
+        if version != FRAME_VERSION {
+            return Err(format!("Unsupported frame version {}, expected {}", version, FRAME_VERSION));
+        }
+        Ok((
+            FrameHeaders {
+                flags: message[1],
+                request_id: u32::from_be_bytes(message[2..6].try_into().unwrap()),
+                length: u64::from_be_bytes(message[6..14].try_into().unwrap()),
+                checksum: u32::from_be_bytes(message[14..18].try_into().unwrap()),
+            },
+            message[HEADERS_LENGTH..].to_vec(),
+        ))
     }
 
-    pub fn add_headers(message: &[u8]) -> Vec<u8> {
-        let length = message.len();
-        if length > u32::MAX as usize {
-            panic!("Maximum allowed length is {}", u32::MAX);
-        }
-        let length_bytes = (length as u32).to_be_bytes();
-        let mut new_message = Vec::new();
-        new_message.extend(length_bytes);
+    pub fn add_headers(message: &[u8], request_id: u32, flags: u8) -> Result<Vec<u8>, String> {
+        let length = u64::try_from(message.len())
+            .map_err(|_| format!("Maximum allowed length is {}", u64::MAX))?;
+        let mut new_message = Vec::with_capacity(HEADERS_LENGTH + message.len());
+        new_message.push(FRAME_VERSION);
+        new_message.push(flags);
+        new_message.extend(request_id.to_be_bytes());
+        new_message.extend(length.to_be_bytes());
+        new_message.extend(crc32fast::hash(message).to_be_bytes());
         new_message.extend(message);
-        return new_message;
+        Ok(new_message)
+    }
+
+    pub fn verify_checksum(message: &[u8], checksum: u32) -> Result<(), String> {
+        let actual_checksum = crc32fast::hash(message);
+        if actual_checksum == checksum {
+            Ok(())
+        } else {
+            Err(format!("Corrupted message, expected checksum {:08x}, got {:08x}", checksum, actual_checksum))
+        }
     }
 }
@@ -1,22 +1,42 @@
+use std::future::Future;
 use std::io;
 use std::net::SocketAddr;
+use std::pin::Pin;
 
 use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
 
 use super::custom_tcp_stream::CustomTcpStream;
 
+/// The connection which is accepted, but might still be going through the TLS handshake
+pub type PendingTcpStream = Pin<Box<dyn Future<Output = Result<CustomTcpStream, String>> + Send>>;
+
 pub struct CustomTcpListener {
     listener: TcpListener,
+    max_message_size: usize,
+    tls_acceptor: Option<TlsAcceptor>,
 }
 
 impl CustomTcpListener {
-    pub async fn new(addr: SocketAddr) -> io::Result<Self> {
+    pub async fn new(addr: SocketAddr, max_message_size: usize, tls_acceptor: Option<TlsAcceptor>) -> io::Result<Self> {
         let listener = TcpListener::bind(addr).await?;
-        Ok(CustomTcpListener {listener})
+        Ok(CustomTcpListener {listener, max_message_size, tls_acceptor})
     }
 
-    pub async fn accept(&self) -> io::Result<CustomTcpStream> {
-        let (stream, _) = self.listener.accept().await?;
-        Ok(CustomTcpStream::new(stream))
+    /// The TLS handshake is left to the returned future, so that a slow client doesn't hold the
+    /// next ones from being accepted
+    pub async fn accept(&self) -> io::Result<PendingTcpStream> {
+        let (stream, peer) = self.listener.accept().await?;
+        let max_message_size = self.max_message_size;
+        Ok(match self.tls_acceptor.clone() {
+            Some(tls_acceptor) => Box::pin(async move {
+                let tls_stream = tls_acceptor
+                    .accept(stream)
+                    .await
+                    .map_err(|e| format!("TLS handshake with {} failed: {}", peer, e))?;
+                Ok(CustomTcpStream::from_io(tls_stream, peer, max_message_size))
+            }),
+            None => Box::pin(async move { Ok(CustomTcpStream::new(stream, peer, max_message_size)) }),
+        })
     }
 }
@@ -0,0 +1,86 @@
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use super::custom_tcp_headers_processor::{CustomTcpHeadersProcessor, FrameHeaders, COMPRESSED_FLAG, HEADERS_LENGTH, MORE_FRAMES_FLAG};
+
+// TODO check the constants
+const MAX_BATCH_SIZE: usize = 100;
+
+/// Read half of either a plain or a TLS connection
+pub type TcpReadHalf = Box<dyn AsyncRead + Unpin + Send>;
+
+pub struct CustomTcpReader {
+    stream: TcpReadHalf,
+    max_message_size: usize,
+    /// Read by `wait_for_message` ahead of the rest of the message
+    first_byte: Option<u8>,
+}
+
+impl CustomTcpReader {
+    pub fn new(stream: TcpReadHalf, max_message_size: usize) -> Self {
+        CustomTcpReader { stream, max_message_size, first_byte: None }
+    }
+
+    /// The connection itself, for relaying the raw bytes. Nothing is buffered by the reader, so
+    /// everything after the last read message is still there
+    pub fn into_inner(self) -> TcpReadHalf {
+        self.stream
+    }
+
+    /// Waiting until the next message starts. Unlike reading the whole message, this can be
+    /// cancelled without losing anything, as at most a single byte is read and then kept
+    pub async fn wait_for_message(&mut self) -> Result<(), String> {
+        if self.first_byte.is_none() {
+            let mut byte = [0; 1];
+            let count = self.stream.read(&mut byte).await.map_err(|e| e.to_string())?;
+            if count == 0 {
+                return Err("Issue with the TCP read, got 0 bytes".to_owned());
+            }
+            self.first_byte = Some(byte[0]);
+        }
+        Ok(())
+    }
+
+    /// Returns the request ID of the message and the message itself.
+    /// Corrupted messages are failing the read, as the headers can't be trusted either after that
+    pub async fn read_full_tcp_message(&mut self) -> Result<(u32, Vec<u8>), String> {
+        let mut overall_message = Vec::new();
+        let (headers, current_body) = self.first_tcp_read_with_headers().await?;
+        let overall_length = headers.length;
+        if overall_length > self.max_message_size as u64 {
+            return Err(format!("The maximum message size is {}, you gave bigger message", self.max_message_size));
+        }
+        if headers.flags & MORE_FRAMES_FLAG != 0 {
+            return Err("Requests split into several frames are not supported".to_owned());
+        }
+        if headers.flags & COMPRESSED_FLAG != 0 {
+            return Err("Compressed requests are not supported".to_owned());
+        }
+        overall_message.extend(current_body);
+        while overall_message.len() < overall_length as usize {
+            overall_message.extend(self.raw_tcp_read(overall_length as usize - overall_message.len()).await?);
+        }
+        CustomTcpHeadersProcessor::verify_checksum(&overall_message, headers.checksum)?;
+        Ok((headers.request_id, overall_message))
+    }
+
+    /// Reading exactly the headers, so that nothing from the body or from the next message is lost
+    async fn first_tcp_read_with_headers(&mut self) -> Result<(FrameHeaders, Vec<u8>), String> {
+        let mut initial_message: Vec<u8> = self.first_byte.take().into_iter().collect();
+        while initial_message.len() < HEADERS_LENGTH {
+            initial_message.extend(self.raw_tcp_read(HEADERS_LENGTH - initial_message.len()).await?);
+        }
+        CustomTcpHeadersProcessor::parse_headers(initial_message)
+    }
+
+    /// Reading at most `limit` bytes, as the stream can already contain the next pipelined message
+    async fn raw_tcp_read(&mut self, limit: usize) -> Result<Vec<u8>, String> {
+        let mut buffer = [0; MAX_BATCH_SIZE];
+        let limit = limit.min(MAX_BATCH_SIZE);
+        let count = self.stream.read(&mut buffer[..limit]).await.map_err(|e| e.to_string())?;
+        if count == 0 {
+            Err("Issue with the TCP read, got 0 bytes".to_owned())
+        } else {
+            Ok(buffer[..count].to_vec())
+        }
+    }
+}
@@ -0,0 +1,42 @@
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use super::custom_tcp_headers_processor::CustomTcpHeadersProcessor;
+
+/// Write half of either a plain or a TLS connection
+pub type TcpWriteHalf = Box<dyn AsyncWrite + Unpin + Send>;
+
+pub struct CustomTcpWriter {
+    stream: TcpWriteHalf,
+}
+
+impl CustomTcpWriter {
+    pub fn new(stream: TcpWriteHalf) -> Self {
+        CustomTcpWriter { stream }
+    }
+
+    /// The connection itself, for relaying the raw bytes
+    pub fn into_inner(self) -> TcpWriteHalf {
+        self.stream
+    }
+
+    /// The response is tagged with the ID of the request it answers
+    pub async fn write_full_message(&mut self, request_id: u32, message: &[u8]) -> Result<(), String> {
+        self.write_frame(request_id, 0, message).await
+    }
+
+    pub async fn write_frame(&mut self, request_id: u32, flags: u8, message: &[u8]) -> Result<(), String> {
+        let buf = CustomTcpHeadersProcessor::add_headers(message, request_id, flags)?;
+        let mut index = 0;
+        while index < buf.len() {
+            let count = self.stream.write(&buf[index..])
+                .await
+                .map_err(|e| format!("Failed sending TCP message: {}", e))?;
+            index += count;
+        }
+        // TLS connections are buffering the written data
+        self.stream
+            .flush()
+            .await
+            .map_err(|e| format!("Failed sending TCP message: {}", e))
+    }
+}
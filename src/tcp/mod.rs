@@ -1,4 +1,7 @@
 pub mod custom_tcp_listener;
 pub mod custom_tcp_stream;
+pub mod custom_tcp_reader;
+pub mod custom_tcp_writer;
 pub mod custom_tcp_headers_processor;
-pub mod tcp_server;
\ No newline at end of file
+pub mod tcp_server;
+pub mod tls;
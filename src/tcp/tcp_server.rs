@@ -1,72 +1,410 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use reqwest::StatusCode;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+
+use crate::authenticator::Authenticator;
+use crate::config::TcpConfig;
+use crate::compression::{Compression, COMPRESSION_CAPABILITY};
+use crate::handshake::{Handshake, HandshakeOutcome, SessionParameters};
+use crate::proxy_response::ProxyResponse;
+use crate::proxy_response_stream::ProxyResponseStream;
+use crate::tunnel_relay::TunnelRelay;
 use crate::{proxy_logic::ProxyLogic, toolkit};
 
-use super::{custom_tcp_listener::CustomTcpListener, custom_tcp_stream::CustomTcpStream};
+use super::{
+    custom_tcp_headers_processor::{COMPRESSED_FLAG, ERROR_FLAG, MORE_FRAMES_FLAG},
+    custom_tcp_listener::CustomTcpListener, custom_tcp_reader::CustomTcpReader,
+    custom_tcp_stream::CustomTcpStream, custom_tcp_writer::CustomTcpWriter,
+};
+
+const BYE_MESSAGE: &str = "BYE";
+const BYE_RESPONSE: &str = "BYE";
+/// `TUNNEL:host:port` switches the session into relaying the raw bytes to the target
+const TUNNEL_PREFIX: &str = "TUNNEL:";
+/// Also bounding the memory used for the streamed responses, as the request tasks are waiting
+/// for the writer when the channel is full
+const RESPONSES_CHANNEL_SIZE: usize = 100;
+/// Requests of a session processed at the same time, the next ones are read once some of them
+/// are answered
+const MAX_REQUESTS_IN_FLIGHT: usize = 32;
+/// Several requests of the session are processed at the same time, otherwise one after another
+const MULTIPLEXING_CAPABILITY: &str = "multiplexing";
+/// The responses come in several frames while being downloaded, otherwise in a single frame
+const STREAMING_CAPABILITY: &str = "streaming";
+/// Optional features the TCP server can agree on during the greeting
+const CAPABILITIES: &[&str] = &[MULTIPLEXING_CAPABILITY, STREAMING_CAPABILITY, COMPRESSION_CAPABILITY];
+/// The greeting and the answer to the authentication challenge
+const MAX_GREETING_ROUNDS: usize = 2;
+
+/// Request ID, flags and content of a frame to be written
+type ResponseFrame = (u32, u8, Vec<u8>);
+
+/// How the responses of the session are written, as agreed during the greeting
+#[derive(Clone, Copy)]
+struct ResponseFeatures {
+    compression: Option<Compression>,
+    streaming: bool,
+    /// Without the streaming, the responses are read into memory up to this size
+    max_response_size: usize,
+}
 
-const CONNECT_MESSAGE: &'static str = "Connect";
-const ACCEPT_RESPONSE: &'static str = "Accept";
-const BYE_MESSAGE: &'static str = "BYE";
-const BYE_RESPONSE: &'static str = "BYE";
+/// How the framed part of a session ends, along with the ID of the request ending it
+enum SessionEnd {
+    Bye(u32),
+    Tunnel(u32, TcpStream),
+}
 
-pub struct TcpServer {}
+pub struct TcpServer {
+    proxy_logic: Arc<ProxyLogic>,
+    /// The session is closed if the client doesn't send anything during this period while having
+    /// no requests in flight
+    idle_timeout: Duration,
+    max_response_size: usize,
+    authenticator: Arc<Authenticator>,
+}
 
 impl TcpServer {
+    pub fn new(proxy_logic: Arc<ProxyLogic>, authenticator: Arc<Authenticator>, config: &TcpConfig) -> Self {
+        TcpServer {
+            proxy_logic,
+            idle_timeout: config.idle_timeout(),
+            max_response_size: config.max_response_size,
+            authenticator,
+        }
+    }
+
     pub async fn start(
         &self,
         listener: CustomTcpListener,
     ) -> Result<(), Box<dyn std::error::Error>> {
         println!("Starting the TCP server...");
         loop {
-            let stream = listener.accept().await?;
-            tokio::spawn(Self::handle_tcp_client(stream));
+            let pending_stream = listener.accept().await?;
+            let (proxy_logic, idle_timeout, authenticator) = (self.proxy_logic.clone(), self.idle_timeout, self.authenticator.clone());
+            let max_response_size = self.max_response_size;
+            tokio::spawn(async move {
+                let stream = match timeout(idle_timeout, pending_stream).await {
+                    Ok(Ok(stream)) => stream,
+                    Ok(Err(e)) => return println!("{}", e),
+                    Err(_) => return println!("TLS handshake didn't complete in {} seconds", idle_timeout.as_secs()),
+                };
+                Self::handle_tcp_client(stream, proxy_logic, idle_timeout, max_response_size, authenticator).await;
+            });
         }
     }
 
-    async fn handle_tcp_client(mut stream: CustomTcpStream) {
-        let res = Self::process_communication(&mut stream).await;
-        if let Err(e) = res {
-            if let Err(reporting_error) = stream
-                .write_full_message(format!("Error occurred: {}\n", e).as_bytes())
+    async fn handle_tcp_client(
+        stream: CustomTcpStream,
+        proxy_logic: Arc<ProxyLogic>,
+        idle_timeout: Duration,
+        max_response_size: usize,
+        authenticator: Arc<Authenticator>,
+    ) {
+        let peer = stream.peer();
+        let (mut reader, mut writer) = stream.into_split();
+        let (request_id, e, mut writer) = match Self::handle_greeting(&mut writer, &mut reader, &authenticator, peer, idle_timeout).await {
+            Ok(parameters) => match Self::process_communication(reader, writer, proxy_logic, idle_timeout, max_response_size, &parameters).await {
+                Ok(()) => return,
+                Err(failure) => failure,
+            },
+            Err((request_id, e)) => (request_id, e, writer),
+        };
+        if let Err(reporting_error) = writer
+            .write_frame(request_id, ERROR_FLAG, format!("Error occurred: {}\n", e).as_bytes())
+            .await
+        {
+            println!(
+                "Failed reporting about exception {}, got another exception: {}",
+                e, reporting_error
+            );
+        }
+    }
+
+    /// Serving requests until the client says bye or stays idle for too long.
+    /// Every request is processed in its own task and the responses are written back as soon as
+    /// they are ready, tagged with the ID of the request, so they can come out of order. The
+    /// responses are streamed in several frames while being downloaded from the target server.
+    /// Failures of a single request are reported back and the session continues, while
+    /// failures of the connection itself are closing it. When writing fails, the requests in
+    /// flight are aborted, as nobody would read their responses.
+    /// A tunnel request is answered once the responses in flight are written, and the rest of the
+    /// session is the raw bytes relayed to the target. Refused tunnels are answered as any other
+    /// refused request and the session continues
+    async fn process_communication(
+        mut reader: CustomTcpReader,
+        writer: CustomTcpWriter,
+        proxy_logic: Arc<ProxyLogic>,
+        idle_timeout: Duration,
+        max_response_size: usize,
+        parameters: &SessionParameters,
+    ) -> Result<(), (u32, String, CustomTcpWriter)> {
+        let features = ResponseFeatures {
+            compression: Compression::of_session(parameters),
+            streaming: parameters.has_capability(STREAMING_CAPABILITY),
+            max_response_size,
+        };
+        let max_in_flight = if parameters.has_capability(MULTIPLEXING_CAPABILITY) { MAX_REQUESTS_IN_FLIGHT } else { 1 };
+        let (response_sender, response_receiver) = mpsc::channel(RESPONSES_CHANNEL_SIZE);
+        let writer_task = tokio::spawn(Self::write_responses(writer, response_receiver));
+        let in_flight = Arc::new(Semaphore::new(max_in_flight));
+
+        let mut requests = JoinSet::new();
+
+        let result = loop {
+            while requests.try_join_next().is_some() {}
+            let read = tokio::select! {
+                read = Self::read_message_with_idle_timeout(&mut reader, &in_flight, max_in_flight, idle_timeout) => read,
+                _ = response_sender.closed() => {
+                    requests.abort_all();
+                    break Err((0, "Failed writing the responses, closing the session".to_owned()));
+                }
+            };
+            let (request_id, message) = match read {
+                Ok(v) => v,
+                Err(e) => break Err((0, e)),
+            };
+            if message == BYE_MESSAGE.as_bytes() {
+                break Ok(SessionEnd::Bye(request_id));
+            }
+            if let Some(target) = message.strip_prefix(TUNNEL_PREFIX.as_bytes()) {
+                match Self::open_tunnel(&proxy_logic, &toolkit::bytes_to_string(target)).await {
+                    Ok(stream) => break Ok(SessionEnd::Tunnel(request_id, stream)),
+                    Err(refusal) => {
+                        let response = ProxyResponseStream::buffered(refusal);
+                        Self::handle_response(request_id, Ok(response), &response_sender, features).await;
+                        continue;
+                    }
+                }
+            }
+            let permit = in_flight
+                .clone()
+                .acquire_owned()
                 .await
-            {
-                println!(
-                    "Failed reporting about exception {}, got another exception: {}",
-                    e, reporting_error
-                );
+                .expect("The requests semaphore is never closed");
+            let response_sender = response_sender.clone();
+            let proxy_logic = proxy_logic.clone();
+            requests.spawn(async move {
+                Self::handle_the_main_message(&proxy_logic, request_id, &message, &response_sender, features).await;
+                drop(permit);
+            });
+        };
+
+        // Waiting for the in-flight requests to be answered before closing the session
+        drop(response_sender);
+        let mut writer = writer_task
+            .await
+            .expect("TCP responses writer task failed");
+        match result {
+            Ok(SessionEnd::Bye(request_id)) => writer
+                .write_full_message(request_id, BYE_RESPONSE.as_bytes())
+                .await
+                .map_err(|e| (request_id, e, writer)),
+            Ok(SessionEnd::Tunnel(request_id, target)) => {
+                let established = ProxyResponse { status: StatusCode::OK, headers: Default::default(), body: Vec::new() };
+                if let Err(e) = writer.write_full_message(request_id, &established.envelope_head()).await {
+                    return Err((request_id, e, writer));
+                }
+                Self::relay_tunnel(reader, writer, target, idle_timeout).await;
+                Ok(())
             }
+            Err((request_id, e)) => Err((request_id, e, writer)),
         }
     }
 
-    /// Is closing the connection in case of failures, can be improved
-    async fn process_communication(stream: &mut CustomTcpStream) -> Result<(), String> {
-        Self::handle_greeting(stream).await?;
-        Self::handle_the_main_message(stream).await?;
-        Self::handle_bye(stream).await?;
-        Ok(())
+    /// The target is `host:port`, going through the same policy and destination filter as the URLs
+    async fn open_tunnel(proxy_logic: &ProxyLogic, target: &str) -> Result<TcpStream, ProxyResponse> {
+        let (host, port) = target
+            .trim()
+            .rsplit_once(':')
+            .and_then(|(host, port)| Some((host, port.parse::<u16>().ok()?)))
+            .filter(|(host, _)| !host.is_empty())
+            .ok_or_else(|| ProxyResponse::proxy_error(
+                StatusCode::BAD_REQUEST,
+                "url-invalid",
+                format!("Invalid tunnel target {}, use TUNNEL:host:port format", target),
+            ))?;
+        proxy_logic.open_tunnel(host, port).await
     }
 
-    async fn handle_greeting(stream: &mut CustomTcpStream) -> Result<(), String> {
-        let message = toolkit::bytes_to_string(&stream.read_full_tcp_message().await?);
-        if message == CONNECT_MESSAGE {
-            stream.write_full_message(ACCEPT_RESPONSE.as_bytes()).await
-        } else {
-            Err("Expected connect message".to_owned())
+    /// The tunnel is closed once idle for the session idle timeout
+    async fn relay_tunnel(reader: CustomTcpReader, writer: CustomTcpWriter, target: TcpStream, idle_timeout: Duration) {
+        let target_peer = target.peer_addr().map(|a| a.to_string()).unwrap_or_default();
+        match TunnelRelay::relay((reader.into_inner(), writer.into_inner()), target.into_split(), idle_timeout).await {
+            Ok((sent, received)) => println!(
+                "Tunnel to {} closed after sending {} and receiving {} bytes",
+                target_peer, sent, received
+            ),
+            Err(e) => println!("Tunnel to {} failed: {}", target_peer, e),
         }
     }
 
-    async fn handle_the_main_message(stream: &mut CustomTcpStream) -> Result<(), String> {
-        // TODO url validation
-        let message = toolkit::bytes_to_string(&stream.read_full_tcp_message().await?);
-        let url = ProxyLogic::process_message(&message)?;
-        let message_to_send = ProxyLogic::generate_content_to_send(&url).await?;
-        stream.write_full_message(message_to_send.as_slice()).await
+    /// Once a write fails, the session is over, so the channel is closed, which stops the request
+    /// tasks sending their responses and lets the session notice it
+    async fn write_responses(
+        mut writer: CustomTcpWriter,
+        mut response_receiver: Receiver<ResponseFrame>,
+    ) -> CustomTcpWriter {
+        while let Some((request_id, flags, response)) = response_receiver.recv().await {
+            if let Err(e) = writer.write_frame(request_id, flags, response.as_slice()).await {
+                println!("Failed writing response of request {}: {}", request_id, e);
+                response_receiver.close();
+                break;
+            }
+        }
+        writer
     }
 
-    async fn handle_bye(stream: &mut CustomTcpStream) -> Result<(), String> {
-        let message = toolkit::bytes_to_string(&stream.read_full_tcp_message().await?);
-        if message == BYE_MESSAGE {
-            stream.write_full_message(BYE_RESPONSE.as_bytes()).await
-        } else {
-            return Err("Expected bye message".to_owned());
+    /// Only the wait for the next message is retried while requests are in flight, as a message
+    /// can't be read again once its reading was interrupted. A message which doesn't arrive in
+    /// whole within the timeout closes the session
+    async fn read_message_with_idle_timeout(
+        reader: &mut CustomTcpReader,
+        in_flight: &Semaphore,
+        max_in_flight: usize,
+        idle_timeout: Duration,
+    ) -> Result<(u32, Vec<u8>), String> {
+        loop {
+            match timeout(idle_timeout, reader.wait_for_message()).await {
+                Ok(result) => break result?,
+                Err(_) if in_flight.available_permits() < max_in_flight => continue,
+                Err(_) => {
+                    return Err(format!(
+                        "Session was idle for more than {} seconds",
+                        idle_timeout.as_secs()
+                    ))
+                }
+            }
+        }
+        timeout(idle_timeout, reader.read_full_tcp_message())
+            .await
+            .map_err(|_| format!("Message wasn't received in whole in {} seconds", idle_timeout.as_secs()))?
+    }
+
+    /// The client may be challenged to prove its identity, in which case it answers with another
+    /// greeting. Rejections are written back as is and close the session, and so does a greeting
+    /// which doesn't arrive within the idle timeout
+    async fn handle_greeting(
+        writer: &mut CustomTcpWriter,
+        reader: &mut CustomTcpReader,
+        authenticator: &Authenticator,
+        peer: SocketAddr,
+        idle_timeout: Duration,
+    ) -> Result<SessionParameters, (u32, String)> {
+        for _ in 0..MAX_GREETING_ROUNDS {
+            let (request_id, bytes) = timeout(idle_timeout, reader.read_full_tcp_message())
+                .await
+                .map_err(|_| (0, format!("Greeting wasn't received in {} seconds", idle_timeout.as_secs())))?
+                .map_err(|e| (0, e))?;
+            let message = toolkit::bytes_to_string(&bytes);
+            let outcome = Handshake::negotiate(&message, CAPABILITIES, authenticator, peer).map_err(|e| (request_id, e))?;
+            let response = match &outcome {
+                HandshakeOutcome::Accepted(_, response)
+                | HandshakeOutcome::Challenged(response)
+                | HandshakeOutcome::Rejected(response) => response,
+            };
+            writer
+                .write_full_message(request_id, response.as_bytes())
+                .await
+                .map_err(|e| (request_id, e))?;
+            match outcome {
+                HandshakeOutcome::Accepted(parameters, _) => {
+                    println!(
+                        "TCP session with {} ({}) agreed on protocol version {} with capabilities [{}]",
+                        peer,
+                        parameters.client.as_deref().unwrap_or("anonymous"),
+                        parameters.version,
+                        parameters.capabilities.join(",")
+                    );
+                    return Ok(*parameters);
+                }
+                HandshakeOutcome::Challenged(_) => continue,
+                HandshakeOutcome::Rejected(_) => return Err((request_id, "Connection rejected".to_owned())),
+            }
+        }
+        Err((0, "Too many greeting rounds".to_owned()))
+    }
+
+    /// Every chunk of the response goes in a separate frame, the last frame is empty and has no
+    /// more frames flag. Failures are reported in a frame with the error flag, which is the last one
+    async fn handle_the_main_message(
+        proxy_logic: &ProxyLogic,
+        request_id: u32,
+        message: &[u8],
+        response_sender: &Sender<ResponseFrame>,
+        features: ResponseFeatures,
+    ) {
+        let response = match ProxyLogic::process_message(message) {
+            Ok(request) => proxy_logic.fetch(&request).await,
+            Err(e) => Err(e),
+        };
+        Self::handle_response(request_id, response, response_sender, features).await;
+    }
+
+    async fn handle_response(
+        request_id: u32,
+        response: Result<ProxyResponseStream, String>,
+        response_sender: &Sender<ResponseFrame>,
+        features: ResponseFeatures,
+    ) {
+        let result = match response {
+            Ok(response) if features.streaming => Self::stream_response(request_id, response, response_sender, features.compression)
+                .await
+                .map(|()| (0, Vec::new())),
+            Ok(response) => Self::whole_response(response, features.compression, features.max_response_size).await,
+            Err(e) => Err(e),
+        };
+        let (flags, content) = match result {
+            Ok(frame) => frame,
+            Err(e) => (ERROR_FLAG, format!("Error occurred: {}\n", e).into_bytes()),
+        };
+        if let Err(e) = response_sender.send((request_id, flags, content)).await {
+            println!("Failed sending response of request {} to the writer: {}", request_id, e);
+        }
+    }
+
+    /// Without the streaming, the whole response goes in the last frame, so it has to fit in memory
+    async fn whole_response(
+        response: ProxyResponseStream,
+        compression: Option<Compression>,
+        max_response_size: usize,
+    ) -> Result<(u8, Vec<u8>), String> {
+        let content = response.collect_at_most(max_response_size).await?;
+        match compression {
+            Some(compression) => match compression.compress_content(content).await? {
+                (compressed, true) => Ok((COMPRESSED_FLAG, compressed)),
+                (content, false) => Ok((0, content)),
+            },
+            None => Ok((0, content)),
         }
     }
+
+    async fn stream_response(
+        request_id: u32,
+        mut response: ProxyResponseStream,
+        response_sender: &Sender<ResponseFrame>,
+        compression: Option<Compression>,
+    ) -> Result<(), String> {
+        while let Some(chunk) = response.next_chunk().await? {
+            // Every frame is compressed on its own, so that the client can decompress it right away
+            let frame = match compression {
+                Some(compression) => match compression.compress_content(chunk).await? {
+                    (compressed, true) => (request_id, MORE_FRAMES_FLAG | COMPRESSED_FLAG, compressed),
+                    (chunk, false) => (request_id, MORE_FRAMES_FLAG, chunk),
+                },
+                None => (request_id, MORE_FRAMES_FLAG, chunk),
+            };
+            response_sender
+                .send(frame)
+                .await
+                .map_err(|e| format!("The session is closed: {}", e))?;
+        }
+        Ok(())
+    }
 }
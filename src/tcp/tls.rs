@@ -0,0 +1,65 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+use tokio_rustls::rustls::server::AllowAnyAuthenticatedClient;
+use tokio_rustls::rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+use crate::config::TlsConfig;
+
+/// Building the TLS acceptor of the TCP listener from the PEM files. When the client CA is given,
+/// only the clients presenting a certificate signed by it are accepted
+pub fn load_acceptor(config: &TlsConfig) -> Result<TlsAcceptor, String> {
+    let certs = load_certs(&config.cert_path)?;
+    let key = load_key(&config.key_path)?;
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match &config.client_ca_path {
+        Some(client_ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(client_ca_path)? {
+                roots
+                    .add(&cert)
+                    .map_err(|e| format!("Invalid client CA certificate in {}: {}", client_ca_path.display(), e))?;
+            }
+            builder.with_client_cert_verifier(Arc::new(AllowAnyAuthenticatedClient::new(roots)))
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let server_config = builder
+        .with_single_cert(certs, key)
+        .map_err(|e| format!("Invalid TLS certificate or key: {}", e))?;
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+fn load_certs(path: &Path) -> Result<Vec<Certificate>, String> {
+    let certs = rustls_pemfile::certs(&mut open(path)?)
+        .map_err(|e| format!("Failed reading certificates from {}: {}", path.display(), e))?;
+    if certs.is_empty() {
+        return Err(format!("No certificates found in {}", path.display()));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+/// The first PKCS#8, RSA or EC private key in the file
+fn load_key(path: &Path) -> Result<PrivateKey, String> {
+    let mut reader = open(path)?;
+    loop {
+        match rustls_pemfile::read_one(&mut reader)
+            .map_err(|e| format!("Failed reading the private key from {}: {}", path.display(), e))?
+        {
+            Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => return Ok(PrivateKey(key)),
+            Some(_) => continue,
+            None => return Err(format!("No private key found in {}", path.display())),
+        }
+    }
+}
+
+fn open(path: &Path) -> Result<BufReader<File>, String> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| format!("Failed opening {}: {}", path.display(), e))
+}
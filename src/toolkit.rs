@@ -1,3 +1,8 @@
 pub fn bytes_to_string(response: &[u8]) -> String {
     String::from_utf8_lossy(response).to_string()
-}
\ No newline at end of file
+}
+
+/// Comparing secrets without leaking the position of the first difference through the timing
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,64 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::{sleep_until, Instant};
+
+const BUFFER_SIZE: usize = 16 * 1024;
+
+/// Relaying the raw bytes of a tunnel between the client and the target, shared by all the
+/// frontends opening tunnels
+pub struct TunnelRelay {}
+
+impl TunnelRelay {
+    /// Each direction is closed once the other side stops sending, and the whole tunnel is closed
+    /// once nothing is sent in either direction for the idle timeout, so that the abandoned
+    /// tunnels don't stay open forever. Returns the bytes sent to the target and received from it
+    pub async fn relay<CR, CW, TR, TW>(
+        (mut client_read, mut client_write): (CR, CW),
+        (mut target_read, mut target_write): (TR, TW),
+        idle_timeout: Duration,
+    ) -> Result<(u64, u64), String>
+    where
+        CR: AsyncRead + Unpin,
+        CW: AsyncWrite + Unpin,
+        TR: AsyncRead + Unpin,
+        TW: AsyncWrite + Unpin,
+    {
+        let last_activity = Mutex::new(Instant::now());
+        let upstream = Self::copy(&mut client_read, &mut target_write, &last_activity);
+        let downstream = Self::copy(&mut target_read, &mut client_write, &last_activity);
+        let idle = async {
+            loop {
+                let deadline = *last_activity.lock().unwrap() + idle_timeout;
+                if Instant::now() >= deadline {
+                    break;
+                }
+                sleep_until(deadline).await;
+            }
+        };
+        tokio::select! {
+            result = async { tokio::try_join!(upstream, downstream) } => result.map_err(|e| e.to_string()),
+            _ = idle => Err(format!("Tunnel was idle for more than {} seconds", idle_timeout.as_secs())),
+        }
+    }
+
+    async fn copy<R, W>(reader: &mut R, writer: &mut W, last_activity: &Mutex<Instant>) -> std::io::Result<u64>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut buffer = vec![0; BUFFER_SIZE];
+        let mut count = 0;
+        loop {
+            let size = reader.read(&mut buffer).await?;
+            if size == 0 {
+                writer.shutdown().await?;
+                return Ok(count);
+            }
+            writer.write_all(&buffer[..size]).await?;
+            count += size as u64;
+            *last_activity.lock().unwrap() = Instant::now();
+        }
+    }
+}
@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use tokio::sync::Mutex;
+use tokio::time::sleep;
+
+use crate::toolkit;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The cookie has to be given back during this period
+const COOKIE_VALIDITY: Duration = Duration::from_secs(60);
+/// Length of the truncated signature, keeping the retry response short
+const COOKIE_SIGNATURE_LENGTH: usize = 16;
+/// The sending budget of a silent peer is forgotten after this period
+const BUDGET_TTL: Duration = Duration::from_secs(60);
+/// Bounding the memory spent on the spoofed addresses, the addresses coming on top of it get no
+/// budget until some of the known ones are forgotten
+const MAX_BUDGETS: usize = 65536;
+
+struct SendingBudget {
+    bytes: usize,
+    last_received: Instant,
+}
+
+/// Protecting third parties from the reflection and amplification, as the sender address of a
+/// datagram can be spoofed.
+/// The clients prove they own their address by giving back the cookie sent to it, which is
+/// signed with a random server secret, so that the server doesn't keep anything for it. Until
+/// then, at most `amplification_factor` times the bytes received from the address are sent to it
+pub struct AddressValidator {
+    secret: [u8; 32],
+    /// Zero disables the limit
+    amplification_factor: usize,
+    budgets: Arc<Mutex<HashMap<SocketAddr, SendingBudget>>>,
+}
+
+impl AddressValidator {
+    pub fn new(amplification_factor: usize) -> Self {
+        let mut secret = [0; 32];
+        rand::thread_rng().fill_bytes(&mut secret);
+        AddressValidator {
+            secret,
+            amplification_factor,
+            budgets: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn start_loop(&self) {
+        let budgets = self.budgets.clone();
+        tokio::spawn(async move {
+            loop {
+                sleep(BUDGET_TTL).await;
+                budgets
+                    .lock()
+                    .await
+                    .retain(|_, budget| budget.last_received.elapsed() < BUDGET_TTL);
+            }
+        });
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.amplification_factor > 0
+    }
+
+    /// The most the server is ready to send back to an unvalidated address for the request
+    pub fn response_limit(&self, request_size: usize) -> Option<usize> {
+        if self.is_enabled() {
+            Some(request_size.saturating_mul(self.amplification_factor))
+        } else {
+            None
+        }
+    }
+
+    /// The cookie is `<timestamp>.<signature>`
+    pub fn create_cookie(&self, peer: SocketAddr) -> String {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        format!("{}.{}", timestamp, hex::encode(self.sign(peer, timestamp)))
+    }
+
+    pub fn verify_cookie(&self, peer: SocketAddr, cookie: &str) -> bool {
+        let (timestamp, signature) = match cookie.split_once('.') {
+            Some(v) => v,
+            None => return false,
+        };
+        let (timestamp, signature) = match (timestamp.parse::<u64>(), hex::decode(signature)) {
+            (Ok(timestamp), Ok(signature)) => (timestamp, signature),
+            _ => return false,
+        };
+        let issued_at = UNIX_EPOCH + Duration::from_secs(timestamp);
+        SystemTime::now() <= issued_at + COOKIE_VALIDITY
+            && toolkit::constant_time_eq(&self.sign(peer, timestamp), &signature)
+    }
+
+    /// Called for every datagram received from an unvalidated address
+    pub async fn record_received(&self, peer: SocketAddr, size: usize) {
+        if !self.is_enabled() {
+            return;
+        }
+        let mut budgets = self.budgets.lock().await;
+        if budgets.len() >= MAX_BUDGETS && !budgets.contains_key(&peer) {
+            return;
+        }
+        let budget = budgets
+            .entry(peer)
+            .or_insert(SendingBudget { bytes: 0, last_received: Instant::now() });
+        budget.bytes = budget.bytes.saturating_add(size.saturating_mul(self.amplification_factor));
+        budget.last_received = Instant::now();
+    }
+
+    /// Returns whether the datagram can be sent to the unvalidated address, spending the budget if so
+    pub async fn try_spend(&self, peer: SocketAddr, size: usize) -> bool {
+        if !self.is_enabled() {
+            return true;
+        }
+        match self.budgets.lock().await.get_mut(&peer) {
+            Some(budget) if budget.bytes >= size => {
+                budget.bytes -= size;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn sign(&self, peer: SocketAddr, timestamp: u64) -> Vec<u8> {
+        let mut mac = HmacSha256::new_from_slice(&self.secret).expect("HMAC accepts keys of any size");
+        mac.update(format!("{}|{}", peer, timestamp).as_bytes());
+        mac.finalize().into_bytes()[..COOKIE_SIGNATURE_LENGTH].to_vec()
+    }
+}
@@ -7,23 +7,26 @@ use crate::udp::batches_cache::BatchesCache;
 
 pub struct AutocleaningBatchesCache {
     batches_cache: Arc<RwLock<BatchesCache>>,
+    ttl: Duration,
 }
 
 impl AutocleaningBatchesCache {
-    pub fn new() -> Self {
+    pub fn new(ttl: Duration) -> Self {
         AutocleaningBatchesCache {
-            batches_cache: Arc::new(RwLock::new(BatchesCache::new()))
+            batches_cache: Arc::new(RwLock::new(BatchesCache::new(ttl))),
+            ttl,
         }
     }
 
-    /// Cleaning up the cache every 5 minutes. Under high loads this might not be enough and
+    /// Cleaning up the cache once per TTL (5 minutes by default). Under high loads this might not be enough and
     /// better monitoring might be required (such as checking the memory usage, etc). Currently going with this.
     /// This can be considered as another improvement opportunity.
     pub fn start_loop(&self) {
         let batches_cache = self.batches_cache.clone();
+        let ttl = self.ttl;
         tokio::spawn(async move {
             loop {
-                sleep(Duration::new(60 * 5, 0)).await;
+                sleep(ttl).await;
                 {
                     batches_cache
                         .write()
@@ -35,18 +38,18 @@ impl AutocleaningBatchesCache {
         });
     }
 
-    pub async fn add_batch(&mut self, peer: SocketAddr, batch_id: u32, batch: Vec<u8>) {
+    pub async fn add_batch(&mut self, peer: SocketAddr, transfer_id: u32, batch_id: u32, batch: Vec<u8>) {
         self.batches_cache
             .write()
             .await
-            .add_batch(peer, batch_id, batch)
+            .add_batch(peer, transfer_id, batch_id, batch)
             .await
     }
-    pub async fn request_batch(&mut self, peer: SocketAddr, batch_id: u32) -> Option<Vec<u8>> {
+    pub async fn request_batch(&mut self, peer: SocketAddr, transfer_id: u32, batch_id: u32) -> Option<Vec<u8>> {
         self.batches_cache
             .read()
             .await
-            .request_batch(peer, batch_id)
+            .request_batch(peer, transfer_id, batch_id)
             .await
     }
 }
@@ -1,13 +1,46 @@
 use regex::Regex;
 
-const REPEAT_BATCH_PREXIT: &'static str = "REPEAT_BATCH:";
+const REPEAT_BATCH_PREXIT: &str = "REPEAT_BATCH:";
 
 pub fn is_batch_repeat_request(message: &str) -> bool {
     message.starts_with(REPEAT_BATCH_PREXIT)
 }
 
-pub fn get_batch_id_for_repeat(message: &str) -> Option<u32> {
-    let re = Regex::new(&("^".to_owned() + REPEAT_BATCH_PREXIT + r"(?P<id>\d+)$")).unwrap();
-    re.captures(&message)
-        .map(|c| c["id"].parse().unwrap())
+/// Parsing `REPEAT_BATCH:<transfer_id>:<batch_id>` into the transfer ID and the batch ID
+pub fn get_batch_id_for_repeat(message: &str) -> Option<(u32, u32)> {
+    let re = Regex::new(&("^".to_owned() + REPEAT_BATCH_PREXIT + r"(?P<transfer>\d+):(?P<id>\d+)$")).unwrap();
+    re.captures(message)
+        .and_then(|c| Some((c["transfer"].parse().ok()?, c["id"].parse().ok()?)))
+}
+
+const REPEAT_BATCHES_PREFIX: &str = "REPEAT_BATCHES:";
+/// Limiting how many batches can be requested at once, so that a tiny request can't trigger
+/// an unbounded amount of retransmissions
+pub const MAX_BATCHES_PER_REPEAT: usize = 10000;
+
+pub fn is_batches_repeat_request(message: &str) -> bool {
+    message.starts_with(REPEAT_BATCHES_PREFIX)
+}
+
+/// Parsing `REPEAT_BATCHES:<transfer_id>:<ranges>` into the transfer ID and the list of missing
+/// batch IDs, where the ranges are comma separated IDs or inclusive ranges, e.g. `0-3,7,9-12`
+pub fn get_batch_ids_for_repeat(message: &str) -> Option<(u32, Vec<u32>)> {
+    let re = Regex::new(&("^".to_owned() + REPEAT_BATCHES_PREFIX + r"(?P<transfer>\d+):(?P<ranges>\d+(-\d+)?(,\d+(-\d+)?)*)$")).unwrap();
+    let captures = re.captures(message)?;
+    let transfer_id = captures["transfer"].parse().ok()?;
+    let mut batch_ids = Vec::new();
+    for range in captures["ranges"].split(',') {
+        let (start, end): (u32, u32) = match range.split_once('-') {
+            Some((start, end)) => (start.parse().ok()?, end.parse().ok()?),
+            None => {
+                let id = range.parse().ok()?;
+                (id, id)
+            }
+        };
+        if start > end || batch_ids.len() + (end - start) as usize >= MAX_BATCHES_PER_REPEAT {
+            return None;
+        }
+        batch_ids.extend(start..=end);
+    }
+    Some((transfer_id, batch_ids))
 }
@@ -5,28 +5,32 @@ use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 use tokio::sync::RwLock;
 
+/// Keyed by the transfer ID, the batch ID and the peer
+type BatchesMap = HashMap<(u32, u32, SocketAddr), (SystemTime, Vec<u8>)>;
+
 // TODO documentation
 pub struct BatchesCache {
-    recent_batches: Arc<RwLock<HashMap<(u32, SocketAddr), (SystemTime, Vec<u8>)>>>,
+    recent_batches: Arc<RwLock<BatchesMap>>,
+    ttl: Duration,
 }
 
 impl BatchesCache {
-    pub fn new() -> Self {
-        BatchesCache { recent_batches: Arc::new(RwLock::new(HashMap::new())) }
+    pub fn new(ttl: Duration) -> Self {
+        BatchesCache { recent_batches: Arc::new(RwLock::new(HashMap::new())), ttl }
     }
 
-    pub async fn add_batch(&mut self, peer: SocketAddr, batch_id: u32, batch: Vec<u8>) {
+    pub async fn add_batch(&mut self, peer: SocketAddr, transfer_id: u32, batch_id: u32, batch: Vec<u8>) {
         self.recent_batches
             .write()
             .await
-            .insert((batch_id, peer), (SystemTime::now().add(Duration::new(60 * 5, 0)), batch));
+            .insert((transfer_id, batch_id, peer), (SystemTime::now().add(self.ttl), batch));
     }
 
-    pub async fn request_batch(&self, peer: SocketAddr, batch_id: u32) -> Option<Vec<u8>> {
+    pub async fn request_batch(&self, peer: SocketAddr, transfer_id: u32, batch_id: u32) -> Option<Vec<u8>> {
         self.recent_batches
             .read()
             .await
-            .get(&(batch_id, peer))
+            .get(&(transfer_id, batch_id, peer))
             .map(|v| v.1.clone())
     }
 
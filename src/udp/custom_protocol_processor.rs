@@ -1,15 +1,44 @@
-pub const HEADERS_BYTES_COUNT: usize = 4 * 2;
+pub const HEADERS_BYTES_COUNT: usize = 4 * 4;
+/// Used for the messages which are not part of any transfer, such as failure reports
+pub const NO_TRANSFER_ID: u32 = 0;
+/// Set in the overall batches count when the content of the transfer is compressed with the
+/// algorithm agreed during the greeting
+pub const COMPRESSED_FLAG: u32 = 1 << 31;
+/// Once agreed during the greeting, every following request of the client starts with the CRC32
+/// of the rest of the datagram as well
+pub const CHECKSUMS_CAPABILITY: &str = "checksums";
+const CHECKSUM_BYTES_COUNT: usize = 4;
 
 pub struct CustomProtocolProcessor {
 
 }
 
+/// Every batch starts with the ID of the transfer it belongs to, so that the batches of
+/// concurrent requests from the same peer don't mix up, then the batch ID, the overall
+/// batches count of the transfer and the CRC32 of the batch content, so that the client can
+/// detect corrupted batches and request them again
 impl CustomProtocolProcessor {
-    pub fn add_headers(batch: &[u8], batch_id: u32, overall_batches: u32) -> Vec<u8> {
+    pub fn add_headers(batch: &[u8], transfer_id: u32, batch_id: u32, overall_batches: u32) -> Vec<u8> {
         let mut current_batch = Vec::new();
+        current_batch.extend(u32::to_be_bytes(transfer_id));
         current_batch.extend(u32::to_be_bytes(batch_id));
         current_batch.extend(u32::to_be_bytes(overall_batches));
+        current_batch.extend(u32::to_be_bytes(crc32fast::hash(batch)));
         current_batch.extend(batch);
-        return current_batch;
+        current_batch
+    }
+
+    /// Removing the CRC32 the client put in front of its request, refusing the request when it
+    /// doesn't match, so that the client sends it again
+    pub fn remove_checksum(datagram: &[u8]) -> Result<Vec<u8>, String> {
+        if datagram.len() < CHECKSUM_BYTES_COUNT {
+            return Err("The request is missing its checksum".to_owned());
+        }
+        let (checksum, message) = datagram.split_at(CHECKSUM_BYTES_COUNT);
+        let expected_checksum = u32::from_be_bytes(checksum.try_into().unwrap());
+        if crc32fast::hash(message) != expected_checksum {
+            return Err("The request is corrupted, checksum doesn't match".to_owned());
+        }
+        Ok(message.to_vec())
     }
 }
@@ -1,43 +1,65 @@
-use std::io::ErrorKind::WouldBlock;
 use std::net::SocketAddr;
+use std::sync::Arc;
+
 use tokio::net::UdpSocket;
 
+use crate::udp::address_validator::AddressValidator;
+use crate::udp::custom_protocol_processor::{CustomProtocolProcessor, CHECKSUMS_CAPABILITY};
+use crate::udp::udp_session_cipher::ENCRYPTION_OVERHEAD;
+use crate::udp::udp_sessions::{UdpSessions, CONNECT_PREFIX};
+
 pub struct CustomUdpSocket {
     socket: UdpSocket,
+    max_message_size: usize,
+    sessions: Arc<UdpSessions>,
+    address_validator: Arc<AddressValidator>,
 }
 
-// Using max message size as 10000, as the target server won't even be able to handle even longer URLs.
-// Source: https://stackoverflow.com/questions/417142/what-is-the-maximum-length-of-a-url-in-different-browsers
-const MAX_MESSAGE_SIZE: usize = 10000;
-/// Using MAX_MESSAGE_SIZE * 2, so that we can understand if the actual message is longer than the maximum or not
-const MAX_BATCH_SIZE: usize = MAX_MESSAGE_SIZE * 2;
-
 impl CustomUdpSocket {
-    pub fn new(socket: UdpSocket) -> Self {
-        CustomUdpSocket { socket }
+    /// The datagrams of the encrypted sessions are decrypted and encrypted here, so that the rest
+    /// of the server sees them in plain. The amplification limit of the unvalidated addresses is
+    /// enforced here as well, as every datagram passes through, and so are the checksums of the
+    /// requests when the session agreed on them
+    pub fn new(socket: UdpSocket, max_message_size: usize, sessions: Arc<UdpSessions>, address_validator: Arc<AddressValidator>) -> Self {
+        CustomUdpSocket { socket, max_message_size, sessions, address_validator }
     }
 
-    pub fn try_recv_from_and_validate(&self) -> Result<Option<(Vec<u8>, SocketAddr)>, (SocketAddr, String)> {
-        let mut buffer = [0; MAX_BATCH_SIZE];
-        match self.socket.try_recv_from(&mut buffer) {
-            Ok((size, peer)) => {
-                if size > MAX_MESSAGE_SIZE {
-                    return Err((peer, format!("Invalid message length, max is {}", MAX_MESSAGE_SIZE)));
-                }
-                Ok(Some((buffer[..size].to_vec(), peer)))
-            },
-            // Source: https://docs.rs/tokio/1.14.0/tokio/net/struct.UdpSocket.html#method.try_recv_from
-            Err(e) if e.kind() == WouldBlock => {
-                Ok(None)
-            },
-            Err(e) => {
-                println!("Failed while receiving request: {}", e);
-                Ok(None)
-            }
+    /// Waiting for the next datagram. Only the socket is awaited here, so that the wait can be
+    /// cancelled without losing a datagram which was already read
+    pub async fn recv_from(&self) -> Result<(Vec<u8>, SocketAddr), String> {
+        // Using max message size * 2, so that we can understand if the actual message is longer than the maximum or not
+        let mut buffer = vec![0; self.max_message_size * 2 + ENCRYPTION_OVERHEAD];
+        let (size, peer) = self.socket
+            .recv_from(&mut buffer)
+            .await
+            .map_err(|e| format!("Failed while receiving request: {}", e))?;
+        buffer.truncate(size);
+        Ok((buffer, peer))
+    }
+
+    /// Returns the plain message of the received datagram, or the failure to report to the peer
+    pub async fn validate(&self, datagram: Vec<u8>, peer: SocketAddr) -> Result<Vec<u8>, String> {
+        if !self.sessions.is_address_validated(peer).await {
+            self.address_validator.record_received(peer, datagram.len()).await;
+        }
+        if datagram.len() > self.max_message_size + ENCRYPTION_OVERHEAD {
+            return Err(format!("Invalid message length, max is {}", self.max_message_size));
         }
+        let mut message = self.sessions.decrypt_incoming(peer, datagram).await?;
+        if !message.starts_with(CONNECT_PREFIX) && self.sessions.has_capability(peer, CHECKSUMS_CAPABILITY).await {
+            message = CustomProtocolProcessor::remove_checksum(&message)?;
+        }
+        if message.len() > self.max_message_size {
+            return Err(format!("Invalid message length, max is {}", self.max_message_size));
+        }
+        Ok(message)
     }
 
     pub async fn send_to(&self, bytes: &[u8], peer: &SocketAddr) -> Result<(), String> {
+        let bytes = &self.sessions.encrypt_outgoing(*peer, bytes).await?;
+        if !self.sessions.is_address_validated(*peer).await && !self.address_validator.try_spend(*peer, bytes.len()).await {
+            return Err(format!("Dropped {} bytes to {}, the address isn't validated yet and the amplification limit is reached", bytes.len(), peer));
+        }
         let resp = self.socket.send_to(bytes, peer).await;
         match resp {
             Ok(c) => {
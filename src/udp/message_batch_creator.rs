@@ -1,3 +1,5 @@
+use crate::udp::custom_protocol_processor::COMPRESSED_FLAG;
+
 pub struct MessageBatchCreator {
     batch_size: usize,
 }
@@ -10,8 +12,10 @@ impl MessageBatchCreator {
     }
 
     pub fn break_message(&self, message: Vec<u8>) -> Result<Vec<Vec<u8>>, String> {
-        let overall_batches_raw: usize = (message.len() + self.batch_size - 1) / self.batch_size;
-        if overall_batches_raw > u32::MAX.try_into().unwrap() {
+        // Empty message is still sent as a single empty batch, so that the client gets a response
+        let overall_batches_raw: usize = message.len().div_ceil(self.batch_size).max(1);
+        // The highest bit of the overall batches count is the compression flag
+        if overall_batches_raw >= COMPRESSED_FLAG as usize {
             return Err("Very long message, can't break into batches".to_owned());
         }
         let overall_batches = overall_batches_raw as u32;
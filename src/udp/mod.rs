@@ -5,4 +5,8 @@ pub mod autocleaning_batches_cache;
 pub mod message_batch_creator;
 pub mod custom_protocol_processor;
 pub mod batch_repeat_helper;
-pub mod udp_server_tasks_handler;
\ No newline at end of file
+pub mod udp_server_tasks_handler;
+pub mod udp_sessions;
+pub mod udp_session_cipher;
+pub mod address_validator;
+pub mod transfer_target;
\ No newline at end of file
@@ -0,0 +1,14 @@
+use std::net::SocketAddr;
+
+use crate::compression::Compression;
+
+/// Where and how the response of a single request is sent
+pub struct TransferTarget {
+    pub peer: SocketAddr,
+    pub transfer_id: u32,
+    /// Size of the whole datagram, including the headers
+    pub batch_size: usize,
+    /// The most the server is ready to send back, set for the unvalidated addresses
+    pub size_limit: Option<usize>,
+    pub compression: Option<Compression>,
+}
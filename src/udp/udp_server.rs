@@ -1,27 +1,23 @@
 use std::net::SocketAddr;
-use std::time::Duration;
 
+use tokio::sync::mpsc::error::TrySendError;
 use tokio::sync::mpsc::{Receiver, Sender};
-use tokio::time::sleep;
 
-use crate::toolkit;
-use crate::udp::custom_protocol_processor::CustomProtocolProcessor;
+use crate::udp::custom_protocol_processor::{CustomProtocolProcessor, NO_TRANSFER_ID};
 use crate::udp::custom_udp_socket::CustomUdpSocket;
 
 pub struct UdpServer {
     socket: CustomUdpSocket,
-    request_sender: Sender<(String, SocketAddr)>,
+    request_sender: Sender<(Vec<u8>, SocketAddr)>,
     response_receiver: Receiver<(Vec<u8>, SocketAddr)>,
-    idle_loop_counter: u32,
 }
 
 impl UdpServer {
-    pub fn new(socket: CustomUdpSocket, request_sender: Sender<(String, SocketAddr)>, response_receiver: Receiver<(Vec<u8>, SocketAddr)>) -> Self {
+    pub fn new(socket: CustomUdpSocket, request_sender: Sender<(Vec<u8>, SocketAddr)>, response_receiver: Receiver<(Vec<u8>, SocketAddr)>) -> Self {
         UdpServer {
             socket,
             request_sender,
             response_receiver,
-            idle_loop_counter: 0,
         }
     }
     pub async fn start(&mut self) {
@@ -31,52 +27,49 @@ impl UdpServer {
         }
     }
 
+    /// Waiting for either an incoming request or an outgoing response, whichever comes first,
+    /// so that nothing is spent while idle and nothing is delayed while busy. Only the waits
+    /// themselves are raced, the received datagram is handled after the race is decided
     async fn one_loop(&mut self) {
-        let mut request_received = false;
-        let mut response_received = false;
-
-        // This might result in never-decreasing backlog, can be improved in the future
-        match self.socket.try_recv_from_and_validate() {
-            Ok(Some((bytes, peer))) => {
-                // Maybe we can trim the message, but might have unexpected side effects
-                let message = toolkit::bytes_to_string(bytes.as_slice());
-                if let Err(e) = self.request_sender.send((message, peer)).await {
-                    self.report_failure(format!("Failed sending message to the requests queue: {}", e), peer).await;
+        tokio::select! {
+            received = self.socket.recv_from() => {
+                match received {
+                    Ok((datagram, peer)) => match self.socket.validate(datagram, peer).await {
+                        Ok(bytes) => self.queue_request(bytes, peer).await,
+                        Err(exception_message) => self.report_failure(exception_message, peer).await,
+                    },
+                    // Failure is not related to a specific peer, nothing to report
+                    Err(e) => println!("{}", e),
                 }
-                request_received = true;
-            }
-            Ok(None) => {
-                // Did not receive any message, no problem
             }
-            Err((peer, exception_message)) => {
-                self.report_failure(exception_message, peer).await;
+            Some((buffer, peer)) = self.response_receiver.recv() => {
+                println!("Sending {} bytes", buffer.len());
+                if let Err(exception_message) = self.socket.send_to(buffer.as_slice(), &peer).await {
+                    println!("{}", exception_message);
+                    // self.report_failure(exception_message, peer).await;
+                    // This might harm more
+                }
             }
         }
+    }
 
-        while let Ok((buffer, peer)) = self.response_receiver.try_recv() {
-            response_received = true;
-            println!("Sending {} bytes", buffer.len());
-            if let Err(exception_message) = self.socket.send_to(buffer.as_slice(), &peer).await {
-                println!("{}", exception_message);
-                // self.report_failure(exception_message, peer).await;
-                // This might harm more
+    /// The requests queue is bounded and the request is rejected when it's full instead of waiting,
+    /// so that the backlog can't grow and the responses keep flowing under load
+    async fn queue_request(&self, bytes: Vec<u8>, peer: SocketAddr) {
+        match self.request_sender.try_send((bytes, peer)) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                self.report_failure("Server is busy, try again later".to_owned(), peer).await;
             }
-        }
-        // Sleeping the thread to save resources in idle state
-        if !request_received && !response_received {
-            if self.idle_loop_counter < 50 {
-                self.idle_loop_counter += 1;
-            } else {
-                sleep(Duration::from_millis(25)).await;
+            Err(e) => {
+                self.report_failure(format!("Failed sending message to the requests queue: {}", e), peer).await;
             }
-        } else {
-            self.idle_loop_counter = 0;
         }
     }
 
     /// Trying to report failure to the client, if even the reporting fails, just logging
     async fn report_failure(&self, message: String, peer: SocketAddr) {
-        if let Err(reporting_failure_message) = self.socket.send_to(CustomProtocolProcessor::add_headers(message.as_bytes(), 0, 1).as_slice(), &peer).await {
+        if let Err(reporting_failure_message) = self.socket.send_to(CustomProtocolProcessor::add_headers(message.as_bytes(), NO_TRANSFER_ID, 0, 1).as_slice(), &peer).await {
             println!("Failed reporting to the client with message {} about another failure: {}", reporting_failure_message, message);
         }
     }
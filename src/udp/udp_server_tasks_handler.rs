@@ -1,33 +1,73 @@
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::Arc;
 
 use tokio::sync::mpsc::{Receiver, Sender};
 use tokio::sync::RwLock;
 
+use crate::authenticator::Authenticator;
+use crate::compression::COMPRESSION_CAPABILITY;
+use crate::config::UdpConfig;
+use crate::handshake::{Handshake, HandshakeOutcome};
 use crate::proxy_logic::ProxyLogic;
+use crate::toolkit;
+use crate::udp::address_validator::AddressValidator;
 use crate::udp::autocleaning_batches_cache::AutocleaningBatchesCache;
-use crate::udp::batch_repeat_helper::{get_batch_id_for_repeat, is_batch_repeat_request};
-use crate::udp::custom_protocol_processor::{CustomProtocolProcessor, HEADERS_BYTES_COUNT};
+use crate::udp::batch_repeat_helper::{get_batch_id_for_repeat, get_batch_ids_for_repeat, is_batch_repeat_request, is_batches_repeat_request};
+use crate::udp::custom_protocol_processor::{CustomProtocolProcessor, CHECKSUMS_CAPABILITY, COMPRESSED_FLAG, HEADERS_BYTES_COUNT, NO_TRANSFER_ID};
 use crate::udp::message_batch_creator::MessageBatchCreator;
+use crate::udp::transfer_target::TransferTarget;
+use crate::udp::udp_session_cipher::UdpSessionCipher;
+use crate::udp::udp_sessions::UdpSessions;
 
-const CONNECT_MESSAGE: &'static str = "Connect";
-const ACCEPT_RESPONSE: &'static str = "Accept";
-const BYE_MESSAGE: &'static str = "BYE";
-const BYE_RESPONSE: &'static str = "BYE";
-const BUFFER_SIZE: usize = 1000;
+const BYE_MESSAGE: &str = "BYE";
+const BYE_RESPONSE: &str = "BYE";
+/// Optional features the UDP server can agree on during the greeting
+const CAPABILITIES: &[&str] = &[CHECKSUMS_CAPABILITY, BATCH_RANGES_CAPABILITY, ENCRYPTION_CAPABILITY, ADDRESS_VALIDATION_CAPABILITY, COMPRESSION_CAPABILITY];
+/// Allows asking for several batches at once with the ID ranges
+const BATCH_RANGES_CAPABILITY: &str = "batch-ranges";
+/// Its argument is the hex encoded X25519 public key, both from the client and from the server
+const ENCRYPTION_CAPABILITY: &str = "encryption";
+/// Its argument is the cookie the server sent in the retry response
+const ADDRESS_VALIDATION_CAPABILITY: &str = "address-validation";
+/// Answering the greeting asking for the address validation without a valid cookie
+const RETRY_RESPONSE: &str = "Retry";
 
 pub struct UdpServerTasksHandler {
-    request_receiver: Receiver<(String, SocketAddr)>,
+    request_receiver: Receiver<(Vec<u8>, SocketAddr)>,
     response_sender: Sender<(Vec<u8>, SocketAddr)>,
     autocleaning_batches_cache: Arc<RwLock<AutocleaningBatchesCache>>,
+    next_transfer_id: Arc<AtomicU32>,
+    proxy_logic: Arc<ProxyLogic>,
+    batch_size: usize,
+    authenticator: Arc<Authenticator>,
+    sessions: Arc<UdpSessions>,
+    address_validator: Arc<AddressValidator>,
 }
 
 impl UdpServerTasksHandler {
-    pub fn new(request_receiver: Receiver<(String, SocketAddr)>, response_sender: Sender<(Vec<u8>, SocketAddr)>) -> Self {
+    /// Taking the batch size and the batches cache TTL from the config
+    pub fn new(request_receiver: Receiver<(Vec<u8>, SocketAddr)>, response_sender: Sender<(Vec<u8>, SocketAddr)>, proxy_logic: Arc<ProxyLogic>, config: &UdpConfig, authenticator: Arc<Authenticator>, sessions: Arc<UdpSessions>, address_validator: Arc<AddressValidator>) -> Self {
         UdpServerTasksHandler {
             request_receiver,
             response_sender,
-            autocleaning_batches_cache: Arc::new(RwLock::new(AutocleaningBatchesCache::new())),
+            autocleaning_batches_cache: Arc::new(RwLock::new(AutocleaningBatchesCache::new(config.cache_ttl()))),
+            next_transfer_id: Arc::new(AtomicU32::new(NO_TRANSFER_ID + 1)),
+            proxy_logic,
+            batch_size: config.batch_size,
+            authenticator,
+            sessions,
+            address_validator,
+        }
+    }
+
+    /// Every response gets its own transfer ID, skipping the reserved one when wrapping around
+    fn generate_transfer_id(next_transfer_id: &AtomicU32) -> u32 {
+        loop {
+            let transfer_id = next_transfer_id.fetch_add(1, Ordering::Relaxed);
+            if transfer_id != NO_TRANSFER_ID {
+                return transfer_id;
+            }
         }
     }
 
@@ -36,33 +76,63 @@ impl UdpServerTasksHandler {
         {
             self.autocleaning_batches_cache.read().await.start_loop();
         }
+        self.sessions.start_loop();
+        self.address_validator.start_loop();
         while let Some((message, peer)) = self.request_receiver.recv().await {
             let response_sender = self.response_sender.clone();
             let autocleaning_batches_cache = self.autocleaning_batches_cache.clone();
+            let batch_size = self.batch_size;
+            let proxy_logic = self.proxy_logic.clone();
+            let next_transfer_id = self.next_transfer_id.clone();
+            let authenticator = self.authenticator.clone();
+            let sessions = self.sessions.clone();
+            let address_validator = self.address_validator.clone();
             tokio::spawn(async move {
-                let message_str = message.as_str();
+                // The control messages are plain text, the requests are parsed from the bytes
+                let message_string = toolkit::bytes_to_string(&message);
+                let message_str = message_string.as_str();
                 match message_str {
-                    CONNECT_MESSAGE => {
-                        if let Err(e) = response_sender.send((ACCEPT_RESPONSE.as_bytes().to_vec(), peer))
+                    _ if Handshake::is_connect_message(message_str) => {
+                        let response = Self::handle_greeting(message_str, peer, &authenticator, &sessions, &address_validator).await;
+                        if let Err(e) = response_sender.send((response.into_bytes(), peer))
                             .await {
                             println!("Failed sending response back: {}", e);
                         }
                     }
                     BYE_MESSAGE => {
+                        if let Some(parameters) = sessions.close(peer).await {
+                            println!("UDP peer {} ({}) closed the session", peer, parameters.client.as_deref().unwrap_or("anonymous"));
+                        }
                         if let Err(e) = response_sender.send((BYE_RESPONSE.as_bytes().to_vec(), peer))
                             .await {
                             println!("Failed sending response back: {}", e);
                         }
                     }
+                    _ if !sessions.touch(peer).await && authenticator.is_enabled() => {
+                        let message = "Failed processing your request: Authentication required, connect first";
+                        if let Err(e) = response_sender
+                            .send((CustomProtocolProcessor::add_headers(message.as_bytes(), NO_TRANSFER_ID, 0, 1), peer))
+                            .await {
+                            println!("Failed sending response back: {}", e);
+                        }
+                    }
+                    _ if is_batches_repeat_request(message_str) && !sessions.has_capability(peer, BATCH_RANGES_CAPABILITY).await => {
+                        let message = "Failed processing your request: Batch ranges weren't agreed, connect with the batch-ranges capability first";
+                        if let Err(e) = response_sender
+                            .send((CustomProtocolProcessor::add_headers(message.as_bytes(), NO_TRANSFER_ID, 0, 1), peer))
+                            .await {
+                            println!("Failed sending response back: {}", e);
+                        }
+                    }
                     _ => {
                         if is_batch_repeat_request(message_str) {
-                            if let Some(id) = get_batch_id_for_repeat(message_str) {
+                            if let Some((requested_transfer_id, id)) = get_batch_id_for_repeat(message_str) {
                                 let bytes_to_send = autocleaning_batches_cache
                                     .write()
                                     .await
-                                    .request_batch(peer, id)
+                                    .request_batch(peer, requested_transfer_id, id)
                                     .await
-                                    .unwrap_or(format!("Couldn't get the requested batch with ID {}", id).as_bytes().to_vec());
+                                    .unwrap_or(format!("Couldn't get the requested batch with ID {} of transfer {}", id, requested_transfer_id).as_bytes().to_vec());
                                 if let Err(e) = response_sender
                                     .send((bytes_to_send, peer))
                                     .await {
@@ -71,8 +141,25 @@ impl UdpServerTasksHandler {
                             } else {
                                 println!("Invalid message was send, couldn't process. If this was UDP issue, the client will retry.")
                             }
+                        } else if is_batches_repeat_request(message_str) {
+                            if let Some((requested_transfer_id, ids)) = get_batch_ids_for_repeat(message_str) {
+                                Self::repeat_batches(peer, requested_transfer_id, ids, response_sender, autocleaning_batches_cache).await;
+                            } else {
+                                println!("Invalid batches repeat request was send, couldn't process. If this was UDP issue, the client will retry.")
+                            }
                         } else {
-                            if let Err(e) = Self::process_with_failures_logging_on_server(message, peer, response_sender, autocleaning_batches_cache).await {
+                            let target = TransferTarget {
+                                peer,
+                                transfer_id: Self::generate_transfer_id(&next_transfer_id),
+                                batch_size,
+                                size_limit: if sessions.is_address_validated(peer).await {
+                                    None
+                                } else {
+                                    address_validator.response_limit(message.len())
+                                },
+                                compression: sessions.compression(peer).await,
+                            };
+                            if let Err(e) = Self::process_with_failures_logging_on_server(&proxy_logic, message, &target, response_sender, autocleaning_batches_cache).await {
                                 println!("Failed processing a request, failed reporting to the client: {}", e);
                             }
                         }
@@ -82,40 +169,132 @@ impl UdpServerTasksHandler {
         }
     }
 
-    async fn process_with_failures_logging_on_server(message: String, peer: SocketAddr, response_sender: Sender<(Vec<u8>, SocketAddr)>, autocleaning_batches_cache: Arc<RwLock<AutocleaningBatchesCache>>) -> Result<(), String> {
-        if let Err(e) = Self::process_with_failures_reporting_to_client(message, peer, response_sender.clone(), autocleaning_batches_cache.clone()).await {
-            if let Err(reporting_error) = Self::send_message_with_batches(format!("Failed processing your request: {}", e).into_bytes(), peer, response_sender, autocleaning_batches_cache)
+    /// The session is remembered for the peer address once accepted, and the following requests
+    /// from the address are refused without it when the authentication is enabled. As the source
+    /// address of a datagram can be spoofed, anyone spoofing the address of an authenticated
+    /// client can send requests in its session, only the encrypted sessions are safe from that.
+    /// When the client asks for the encryption, the session keys are agreed here as well.
+    /// When it asks for the address validation, the session is opened only once it gives back the
+    /// cookie sent in the retry response, which proves it receives what is sent to its address
+    async fn handle_greeting(message: &str, peer: SocketAddr, authenticator: &Authenticator, sessions: &UdpSessions, address_validator: &AddressValidator) -> String {
+        match Handshake::negotiate(message, CAPABILITIES, authenticator, peer) {
+            Ok(HandshakeOutcome::Accepted(mut parameters, mut response)) => {
+                let address_validated = parameters.has_capability(ADDRESS_VALIDATION_CAPABILITY);
+                if address_validated {
+                    match parameters.client_arguments.get(ADDRESS_VALIDATION_CAPABILITY) {
+                        Some(cookie) if address_validator.verify_cookie(peer, cookie) => {}
+                        _ => return format!("{}:{}", RETRY_RESPONSE, address_validator.create_cookie(peer)),
+                    }
+                }
+                let cipher = if parameters.has_capability(ENCRYPTION_CAPABILITY) {
+                    let client_public_key = match parameters.client_arguments.get(ENCRYPTION_CAPABILITY) {
+                        Some(key) => key,
+                        None => return Handshake::rejection(peer, "The encryption needs the client public key"),
+                    };
+                    let token = parameters.client.as_deref().and_then(|c| authenticator.token(c));
+                    let (cipher, server_public_key) = match UdpSessionCipher::negotiate(client_public_key, token) {
+                        Ok(v) => v,
+                        Err(e) => return Handshake::rejection(peer, &e),
+                    };
+                    parameters.server_arguments.insert(ENCRYPTION_CAPABILITY.to_owned(), server_public_key);
+                    response = parameters.accept_response();
+                    Some(cipher)
+                } else if sessions.requires_encryption() {
+                    return Handshake::rejection(peer, "Encryption is required");
+                } else {
+                    None
+                };
+                println!(
+                    "UDP peer {} ({}) agreed on protocol version {} with capabilities [{}]",
+                    peer,
+                    parameters.client.as_deref().unwrap_or("anonymous"),
+                    parameters.version,
+                    parameters.capabilities.join(",")
+                );
+                sessions.open(peer, *parameters, cipher, address_validated).await;
+                response
+            }
+            Ok(HandshakeOutcome::Challenged(response)) | Ok(HandshakeOutcome::Rejected(response)) => response,
+            Err(e) => format!("Failed processing your request: {}", e),
+        }
+    }
+
+    /// Resending all the requested batches of the transfer at once. The missing ones are reported
+    /// in a single message afterwards, so that the client doesn't wait for them
+    async fn repeat_batches(peer: SocketAddr, transfer_id: u32, batch_ids: Vec<u32>, response_sender: Sender<(Vec<u8>, SocketAddr)>, autocleaning_batches_cache: Arc<RwLock<AutocleaningBatchesCache>>) {
+        let mut missing_batch_ids = Vec::new();
+        for id in batch_ids {
+            let batch = autocleaning_batches_cache
+                .write()
+                .await
+                .request_batch(peer, transfer_id, id)
+                .await;
+            match batch {
+                Some(bytes_to_send) => {
+                    if let Err(e) = response_sender
+                        .send((bytes_to_send, peer))
+                        .await {
+                        println!("Failed sending batch {} of transfer {} to the queue, the client might retry...\n{}", id, transfer_id, e);
+                    }
+                }
+                None => missing_batch_ids.push(id.to_string()),
+            }
+        }
+        if !missing_batch_ids.is_empty() {
+            let message = format!("Couldn't get the requested batches with IDs {} of transfer {}", missing_batch_ids.join(","), transfer_id);
+            if let Err(e) = response_sender
+                .send((message.into_bytes(), peer))
+                .await {
+                println!("Failed sending to the response sender, the client might retry...\n{}", e);
+            }
+        }
+    }
+
+    async fn process_with_failures_logging_on_server(proxy_logic: &ProxyLogic, message: Vec<u8>, target: &TransferTarget, response_sender: Sender<(Vec<u8>, SocketAddr)>, autocleaning_batches_cache: Arc<RwLock<AutocleaningBatchesCache>>) -> Result<(), String> {
+        if let Err(e) = Self::process_with_failures_reporting_to_client(proxy_logic, message, target, response_sender.clone(), autocleaning_batches_cache.clone()).await {
+            if let Err(reporting_error) = Self::send_message_with_batches(format!("Failed processing your request: {}", e).into_bytes(), false, target, response_sender, autocleaning_batches_cache)
                 .await {
-                return Err(format!("Failed sending to the queue: {}", reporting_error.to_string()));
+                return Err(format!("Failed sending to the queue: {}", reporting_error));
             }
         }
         Ok(())
     }
 
-    async fn process_with_failures_reporting_to_client(message: String, peer: SocketAddr, response_sender: Sender<(Vec<u8>, SocketAddr)>, autocleaning_batches_cache: Arc<RwLock<AutocleaningBatchesCache>>) -> Result<(), String> {
-        let url = ProxyLogic::process_message(&message.trim())
-            .map_err(|e| format!("Invalid url, can't parse it: {}", e))?;
-        let message_to_send = ProxyLogic::generate_content_to_send(&url).await
+    /// The responses which wouldn't make it through the amplification limit of the unvalidated
+    /// addresses anyway are refused with a short failure.
+    /// The whole response is compressed before being split into batches, when the session agreed on it
+    async fn process_with_failures_reporting_to_client(proxy_logic: &ProxyLogic, message: Vec<u8>, target: &TransferTarget, response_sender: Sender<(Vec<u8>, SocketAddr)>, autocleaning_batches_cache: Arc<RwLock<AutocleaningBatchesCache>>) -> Result<(), String> {
+        let request = ProxyLogic::process_message(&message)
+            .map_err(|e| format!("Invalid request, can't parse it: {}", e))?;
+        let message_to_send = proxy_logic.generate_content_to_send(&request).await
             .map_err(|e| format!("Issue while loading the data from target server: {}", e))?;
-        println!("Message to send has length {} and the peer is {}", message_to_send.len(), peer);
-        Self::send_message_with_batches(message_to_send, peer, response_sender, autocleaning_batches_cache).await
+        let (message_to_send, compressed) = match target.compression {
+            Some(compression) => compression.compress_content(message_to_send).await?,
+            None => (message_to_send, false),
+        };
+        if target.size_limit.is_some_and(|limit| message_to_send.len() > limit) {
+            return Err("Address not validated, use address-validation".to_owned());
+        }
+        println!("Message to send has length {}, the peer is {} and the transfer ID is {}", message_to_send.len(), target.peer, target.transfer_id);
+        Self::send_message_with_batches(message_to_send, compressed, target, response_sender, autocleaning_batches_cache).await
             .map_err(|e| format!("Failure when sending the message back to the client: {}", e))?;
         Ok(())
     }
 
-    async fn send_message_with_batches(message: Vec<u8>, peer: SocketAddr, response_sender: Sender<(Vec<u8>, SocketAddr)>, autocleaning_batches_cache: Arc<RwLock<AutocleaningBatchesCache>>) -> Result<(), String> {
-        let message_batch_creator = MessageBatchCreator::new(BUFFER_SIZE - HEADERS_BYTES_COUNT);
+    async fn send_message_with_batches(message: Vec<u8>, compressed: bool, target: &TransferTarget, response_sender: Sender<(Vec<u8>, SocketAddr)>, autocleaning_batches_cache: Arc<RwLock<AutocleaningBatchesCache>>) -> Result<(), String> {
+        let (peer, transfer_id) = (target.peer, target.transfer_id);
+        let message_batch_creator = MessageBatchCreator::new(target.batch_size - HEADERS_BYTES_COUNT);
         let batches = message_batch_creator
             .break_message(message)?;
-        let mut index = 0;
-        let batches_count = batches.len();
-        for batch in batches.iter() {
-            let current_batch = CustomProtocolProcessor::add_headers(batch.as_slice(), index as u32, batches_count as u32);
+        let batches_count = batches.len() as u32;
+        let overall_field = if compressed { batches_count | COMPRESSED_FLAG } else { batches_count };
+        for (index, batch) in batches.iter().enumerate() {
+            let current_batch = CustomProtocolProcessor::add_headers(batch.as_slice(), transfer_id, index as u32, overall_field);
             {
                 autocleaning_batches_cache
                     .write()
                     .await
-                    .add_batch(peer, index as u32, current_batch.clone())
+                    .add_batch(peer, transfer_id, index as u32, current_batch.clone())
                     .await;
             }
 
@@ -123,10 +302,8 @@ impl UdpServerTasksHandler {
             if let Err(e) = response_sender
                 .send((current_batch.to_vec(), peer))
                 .await {
-                println!("Failed sending batch {} to the queue: {}", index, e.to_string());
+                println!("Failed sending batch {} of transfer {} to the queue: {}", index, transfer_id, e);
             }
-
-            index += 1;
         }
         Ok(())
     }
@@ -0,0 +1,125 @@
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use rand::rngs::OsRng;
+use sha2::Sha256;
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+/// The counter in front of the ciphertext and the authentication tag after it
+pub const ENCRYPTION_OVERHEAD: usize = COUNTER_LENGTH + 16;
+const COUNTER_LENGTH: usize = 8;
+/// How far behind the newest received datagram the older ones are still accepted, as UDP can
+/// reorder them
+const REPLAY_WINDOW: u64 = 64;
+const KEYS_INFO: &[u8] = b"rust_proxy_server udp session";
+
+/// Authenticated encryption of the datagrams of a single UDP session.
+/// The keys are derived from an X25519 exchange made during the greeting, mixed with the token of
+/// the client when it's authenticated. That keeps anybody without the token from sitting in the
+/// middle only for the HMAC authentication, as the token authentication sends the token in plain.
+/// Every datagram is `[counter u64][ChaCha20-Poly1305 ciphertext with tag]`, the counter being the
+/// nonce. Each direction has its own key and counter, and the replayed datagrams are rejected
+pub struct UdpSessionCipher {
+    receiving: ChaCha20Poly1305,
+    sending: ChaCha20Poly1305,
+    next_sending_counter: u64,
+    highest_received_counter: Option<u64>,
+    /// Bit N is set when the datagram with counter `highest - N` was received
+    received_window: u64,
+}
+
+impl UdpSessionCipher {
+    /// Returns the cipher and the hex encoded public key to give back to the client
+    pub fn negotiate(client_public_key: &str, token: Option<&str>) -> Result<(Self, String), String> {
+        let client_public_key: [u8; 32] = hex::decode(client_public_key)
+            .ok()
+            .and_then(|k| k.try_into().ok())
+            .ok_or_else(|| "Invalid encryption public key, expected 32 hex encoded bytes".to_owned())?;
+        let client_public_key = PublicKey::from(client_public_key);
+        let server_secret = EphemeralSecret::random_from_rng(OsRng);
+        let server_public_key = PublicKey::from(&server_secret);
+        let shared_secret = server_secret.diffie_hellman(&client_public_key);
+        if !shared_secret.was_contributory() {
+            return Err("Invalid encryption public key".to_owned());
+        }
+
+        let mut info = KEYS_INFO.to_vec();
+        info.extend_from_slice(client_public_key.as_bytes());
+        info.extend_from_slice(server_public_key.as_bytes());
+        let mut keys = [0; 64];
+        Hkdf::<Sha256>::new(Some(token.unwrap_or("").as_bytes()), shared_secret.as_bytes())
+            .expand(&info, &mut keys)
+            .map_err(|e| e.to_string())?;
+        let (client_to_server, server_to_client) = keys.split_at(32);
+        let cipher = UdpSessionCipher {
+            receiving: ChaCha20Poly1305::new(Key::from_slice(client_to_server)),
+            sending: ChaCha20Poly1305::new(Key::from_slice(server_to_client)),
+            next_sending_counter: 0,
+            highest_received_counter: None,
+            received_window: 0,
+        };
+        Ok((cipher, hex::encode(server_public_key.as_bytes())))
+    }
+
+    pub fn encrypt(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, String> {
+        let counter = self.next_sending_counter;
+        self.next_sending_counter += 1;
+        let ciphertext = self
+            .sending
+            .encrypt(&Self::nonce(counter), plaintext)
+            .map_err(|_| "Failed encrypting the datagram".to_owned())?;
+        let mut datagram = counter.to_be_bytes().to_vec();
+        datagram.extend(ciphertext);
+        Ok(datagram)
+    }
+
+    pub fn decrypt(&mut self, datagram: &[u8]) -> Result<Vec<u8>, String> {
+        if datagram.len() < ENCRYPTION_OVERHEAD {
+            return Err("Encrypted datagram is too short".to_owned());
+        }
+        let (counter, ciphertext) = datagram.split_at(COUNTER_LENGTH);
+        let counter = u64::from_be_bytes(counter.try_into().unwrap());
+        if self.is_replayed(counter) {
+            return Err("Replayed or too old datagram".to_owned());
+        }
+        let plaintext = self
+            .receiving
+            .decrypt(&Self::nonce(counter), ciphertext)
+            .map_err(|_| "Failed decrypting the datagram".to_owned())?;
+        self.mark_received(counter);
+        Ok(plaintext)
+    }
+
+    fn is_replayed(&self, counter: u64) -> bool {
+        match self.highest_received_counter {
+            Some(highest) if counter <= highest => {
+                let age = highest - counter;
+                age >= REPLAY_WINDOW || self.received_window & (1 << age) != 0
+            }
+            _ => false,
+        }
+    }
+
+    /// Only called for the authenticated datagrams, so that forged counters can't move the window
+    fn mark_received(&mut self, counter: u64) {
+        match self.highest_received_counter {
+            Some(highest) if counter <= highest => self.received_window |= 1 << (highest - counter),
+            Some(highest) => {
+                let shift = counter - highest;
+                self.received_window = if shift >= REPLAY_WINDOW { 0 } else { self.received_window << shift };
+                self.received_window |= 1;
+                self.highest_received_counter = Some(counter);
+            }
+            None => {
+                self.received_window = 1;
+                self.highest_received_counter = Some(counter);
+            }
+        }
+    }
+
+    fn nonce(counter: u64) -> Nonce {
+        let mut nonce = [0; 12];
+        nonce[4..].copy_from_slice(&counter.to_be_bytes());
+        *Nonce::from_slice(&nonce)
+    }
+}
@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::sync::RwLock;
+use tokio::time::sleep;
+
+use crate::compression::Compression;
+use crate::handshake::SessionParameters;
+use crate::udp::udp_session_cipher::UdpSessionCipher;
+
+pub const CONNECT_PREFIX: &[u8] = b"Connect";
+
+struct UdpSession {
+    parameters: SessionParameters,
+    last_seen: Instant,
+    cipher: Option<UdpSessionCipher>,
+    /// The responses are encrypted only after the client sent an encrypted request, so that the
+    /// accept response carrying the server key still goes in plain
+    cipher_confirmed: bool,
+    /// Either the client gave back the address validation cookie or confirmed the session keys,
+    /// both proving it receives what is sent to its address
+    address_validated: bool,
+}
+
+/// Remembering what was agreed with every UDP peer during the greeting, as the following
+/// datagrams don't carry it. The sessions are closed with the bye message or when the peer
+/// stays silent for longer than the TTL.
+/// Shared between the socket, which encrypts and decrypts the datagrams of the encrypted
+/// sessions, and the tasks handler, which opens the sessions
+pub struct UdpSessions {
+    sessions: Arc<RwLock<HashMap<SocketAddr, UdpSession>>>,
+    ttl: Duration,
+    /// Refusing everything but the greeting in plain
+    require_encryption: bool,
+}
+
+impl UdpSessions {
+    pub fn new(ttl: Duration, require_encryption: bool) -> Self {
+        UdpSessions {
+            sessions: Arc::new(RwLock::new(HashMap::new())),
+            ttl,
+            require_encryption,
+        }
+    }
+
+    pub fn start_loop(&self) {
+        let sessions = self.sessions.clone();
+        let ttl = self.ttl;
+        tokio::spawn(async move {
+            loop {
+                sleep(ttl).await;
+                let mut sessions = sessions.write().await;
+                let before = sessions.len();
+                sessions.retain(|_, session| session.last_seen.elapsed() < ttl);
+                println!("Removed {} expired UDP sessions", before - sessions.len());
+            }
+        });
+    }
+
+    pub fn requires_encryption(&self) -> bool {
+        self.require_encryption
+    }
+
+    /// Replacing the previous session of the peer, if any
+    pub async fn open(&self, peer: SocketAddr, parameters: SessionParameters, cipher: Option<UdpSessionCipher>, address_validated: bool) {
+        self.sessions.write().await.insert(
+            peer,
+            UdpSession { parameters, last_seen: Instant::now(), cipher, cipher_confirmed: false, address_validated },
+        );
+    }
+
+    /// The session keys are dropped as well, so the bye response goes in plain
+    pub async fn close(&self, peer: SocketAddr) -> Option<SessionParameters> {
+        self.sessions.write().await.remove(&peer).map(|session| session.parameters)
+    }
+
+    /// Returns whether the peer has a live session, extending it if so
+    pub async fn touch(&self, peer: SocketAddr) -> bool {
+        match self.sessions.write().await.get_mut(&peer) {
+            Some(session) if session.last_seen.elapsed() < self.ttl => {
+                session.last_seen = Instant::now();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub async fn compression(&self, peer: SocketAddr) -> Option<Compression> {
+        self.sessions
+            .read()
+            .await
+            .get(&peer)
+            .and_then(|session| Compression::of_session(&session.parameters))
+    }
+
+    /// The peers without a session have no capabilities
+    pub async fn has_capability(&self, peer: SocketAddr, capability: &str) -> bool {
+        self.sessions
+            .read()
+            .await
+            .get(&peer)
+            .map(|session| session.parameters.has_capability(capability))
+            .unwrap_or(false)
+    }
+
+    pub async fn is_address_validated(&self, peer: SocketAddr) -> bool {
+        self.sessions
+            .read()
+            .await
+            .get(&peer)
+            .map(|session| session.address_validated)
+            .unwrap_or(false)
+    }
+
+    /// The greeting is in plain, as it might be starting a new session. Once the session keys are
+    /// confirmed, a greeting in plain is refused, as anybody could send it with the peer address
+    /// to replace the session, so the client has to send it encrypted or say bye first
+    pub async fn decrypt_incoming(&self, peer: SocketAddr, datagram: Vec<u8>) -> Result<Vec<u8>, String> {
+        let is_greeting = datagram.starts_with(CONNECT_PREFIX);
+        match self.sessions.write().await.get_mut(&peer) {
+            Some(UdpSession { cipher: Some(_), cipher_confirmed: true, .. }) if is_greeting => {
+                Err("The session is encrypted, the greeting has to be encrypted as well".to_owned())
+            }
+            _ if is_greeting => Ok(datagram),
+            Some(UdpSession { cipher: Some(cipher), cipher_confirmed, address_validated, .. }) => {
+                let plaintext = cipher.decrypt(&datagram)?;
+                *cipher_confirmed = true;
+                *address_validated = true;
+                Ok(plaintext)
+            }
+            _ if self.require_encryption => Err("Encryption is required, connect first".to_owned()),
+            _ => Ok(datagram),
+        }
+    }
+
+    pub async fn encrypt_outgoing(&self, peer: SocketAddr, datagram: &[u8]) -> Result<Vec<u8>, String> {
+        match self.sessions.write().await.get_mut(&peer) {
+            Some(UdpSession { cipher: Some(cipher), cipher_confirmed: true, .. }) => cipher.encrypt(datagram),
+            _ => Ok(datagram.to_vec()),
+        }
+    }
+}
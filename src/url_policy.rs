@@ -0,0 +1,152 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+
+use reqwest::Url;
+
+use crate::config::PolicyConfig;
+
+/// Rejection of the URL, the reason code is meant for the clients to act on
+#[derive(Debug)]
+pub struct PolicyViolation {
+    pub reason_code: &'static str,
+    pub message: String,
+}
+
+impl Display for PolicyViolation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl Error for PolicyViolation {}
+
+impl PolicyViolation {
+    fn new(reason_code: &'static str, message: String) -> Self {
+        PolicyViolation { reason_code, message }
+    }
+}
+
+/// Deciding which URLs the proxy is ready to fetch, before anything is resolved or connected
+pub struct UrlPolicy {
+    config: PolicyConfig,
+}
+
+impl UrlPolicy {
+    pub fn new(config: PolicyConfig) -> Self {
+        UrlPolicy { config }
+    }
+
+    pub fn check(&self, url: &str) -> Result<Url, PolicyViolation> {
+        if url.len() > self.config.max_url_length {
+            return Err(PolicyViolation::new(
+                "url-too-long",
+                format!("URL is longer than {} characters", self.config.max_url_length),
+            ));
+        }
+        let parsed_url = Url::parse(url)
+            .map_err(|e| PolicyViolation::new("url-invalid", format!("Invalid URL {}: {}", url, e)))?;
+        self.check_parsed(&parsed_url)?;
+        Ok(parsed_url)
+    }
+
+    /// Used for the redirect targets as well, which are already parsed by the HTTP client
+    pub fn check_parsed(&self, url: &Url) -> Result<(), PolicyViolation> {
+        if !self.config.allowed_schemes.iter().any(|s| s.eq_ignore_ascii_case(url.scheme())) {
+            return Err(PolicyViolation::new(
+                "scheme-not-allowed",
+                format!("Scheme {} is not allowed, use one of {}", url.scheme(), self.config.allowed_schemes.join(", ")),
+            ));
+        }
+        let host = url
+            .host_str()
+            .ok_or_else(|| PolicyViolation::new("url-invalid", format!("URL {} has no host", url)))?;
+        self.check_host_port(host, url.port_or_known_default())
+    }
+
+    /// Used for the tunnels as well, which have no URL
+    pub fn check_host_port(&self, host: &str, port: Option<u16>) -> Result<(), PolicyViolation> {
+        if self.config.denied_hosts.iter().any(|p| Self::host_matches(p, host)) {
+            return Err(PolicyViolation::new("host-denied", format!("Host {} is denied", host)));
+        }
+        if !self.config.allowed_hosts.is_empty()
+            && !self.config.allowed_hosts.iter().any(|p| Self::host_matches(p, host))
+        {
+            return Err(PolicyViolation::new("host-not-allowed", format!("Host {} is not in the allowed hosts", host)));
+        }
+        if let Some(port) = port {
+            if !self.config.allowed_ports.is_empty() && !self.config.allowed_ports.contains(&port) {
+                return Err(PolicyViolation::new("port-not-allowed", format!("Port {} is not allowed", port)));
+            }
+        }
+        Ok(())
+    }
+
+    /// The pattern is either the exact host, or `*.` followed by the domain to match all of its
+    /// subdomains, or `*` to match everything. The fully qualified names with the trailing dot
+    /// resolve to the same host, so it's ignored on both sides
+    fn host_matches(pattern: &str, host: &str) -> bool {
+        if pattern == "*" {
+            return true;
+        }
+        let normalized = |name: &str| name.strip_suffix('.').unwrap_or(name).to_ascii_lowercase();
+        let (pattern, host) = (normalized(pattern), normalized(host));
+        match pattern.strip_prefix("*.") {
+            Some(domain) => host.ends_with(&format!(".{}", domain)),
+            None => pattern == host,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(allowed_hosts: &[&str], denied_hosts: &[&str], allowed_ports: &[u16]) -> UrlPolicy {
+        UrlPolicy::new(PolicyConfig {
+            allowed_hosts: allowed_hosts.iter().map(|h| h.to_string()).collect(),
+            denied_hosts: denied_hosts.iter().map(|h| h.to_string()).collect(),
+            allowed_ports: allowed_ports.to_vec(),
+            ..PolicyConfig::default()
+        })
+    }
+
+    fn reason(result: Result<impl Sized, PolicyViolation>) -> &'static str {
+        result.err().map(|v| v.reason_code).unwrap_or("allowed")
+    }
+
+    #[test]
+    fn exact_and_wildcard_patterns_match_ignoring_case() {
+        let policy = policy(&[], &["internal.corp", "*.secret.example"], &[]);
+        assert_eq!(reason(policy.check("http://Internal.Corp/")), "host-denied");
+        assert_eq!(reason(policy.check("http://a.b.secret.example/")), "host-denied");
+        assert_eq!(reason(policy.check("http://secret.example/")), "allowed");
+        assert_eq!(reason(policy.check("http://notinternal.corp/")), "allowed");
+    }
+
+    #[test]
+    fn trailing_dot_is_ignored() {
+        let policy = policy(&[], &["internal.corp", "*.corp", "other.example."], &[]);
+        assert_eq!(reason(policy.check("http://internal.corp./")), "host-denied");
+        assert_eq!(reason(policy.check("http://a.corp./")), "host-denied");
+        assert_eq!(reason(policy.check_host_port("internal.corp.", Some(22))), "host-denied");
+        assert_eq!(reason(policy.check_host_port("other.example", Some(80))), "host-denied");
+    }
+
+    #[test]
+    fn allowed_hosts_and_ports_restrict_the_targets() {
+        let policy = policy(&["example.com", "*.example.com"], &["admin.example.com"], &[443]);
+        assert_eq!(reason(policy.check("https://www.example.com/")), "allowed");
+        assert_eq!(reason(policy.check("https://example.com./")), "allowed");
+        assert_eq!(reason(policy.check("https://admin.example.com/")), "host-denied");
+        assert_eq!(reason(policy.check("https://example.org/")), "host-not-allowed");
+        assert_eq!(reason(policy.check("http://example.com/")), "port-not-allowed");
+    }
+
+    #[test]
+    fn schemes_and_lengths_are_checked() {
+        let policy = UrlPolicy::new(PolicyConfig { max_url_length: 30, ..PolicyConfig::default() });
+        assert_eq!(reason(policy.check("ftp://example.com/")), "scheme-not-allowed");
+        assert_eq!(reason(policy.check("not a url")), "url-invalid");
+        assert_eq!(reason(policy.check("http://example.com/a/very/long/path")), "url-too-long");
+    }
+}